@@ -0,0 +1,176 @@
+use std::cmp::Reverse;
+
+use crate::data::PostcodeInfo;
+
+// How well the place name of a postcode matches the searched text, best last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum PlaceMatch {
+    // One typo in a word of at least `TYPO_MIN_LENGTH` characters
+    Typo,
+    Substring,
+    WordPrefix,
+    Prefix,
+    Exact,
+}
+
+// Shorter words are matched exactly, a single typo would match too much.
+const TYPO_MIN_LENGTH: usize = 4;
+
+struct Entry {
+    // Five digits with leading zeros
+    zipcode: String,
+    // Normalized, see `normalize`
+    place: String,
+    info: PostcodeInfo,
+}
+
+// Autocompletion of postcodes by their digits, the place name or both.
+pub struct PostcodeIndex {
+    // Sorted by zipcode, so a prefix is a range
+    entries: Vec<Entry>,
+}
+
+// Lower case, umlauts and "ß" spelled without them and anything but letters and digits
+// between words dropped, so "Muenchen", "Munchen" and "München" are all "munchen".
+pub fn normalize(value: &str) -> String {
+    value
+        .to_lowercase()
+        .replace('ä', "a")
+        .replace('ö', "o")
+        .replace('ü', "u")
+        .replace('ß', "ss")
+        .replace("ae", "a")
+        .replace("oe", "o")
+        .replace("ue", "u")
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Levenshtein distance between two short strings.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            current[j + 1] = (previous[j] + usize::from(ca != cb))
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+fn match_place(place: &str, text: &str) -> Option<PlaceMatch> {
+    if place == text {
+        return Some(PlaceMatch::Exact);
+    }
+    if place.starts_with(text) {
+        return Some(PlaceMatch::Prefix);
+    }
+
+    let word_starts: Vec<usize> = std::iter::once(0)
+        .chain(place.match_indices(' ').map(|(i, _)| i + 1))
+        .collect();
+    if word_starts
+        .iter()
+        .any(|start| place[*start..].starts_with(text))
+    {
+        return Some(PlaceMatch::WordPrefix);
+    }
+    if place.contains(text) {
+        return Some(PlaceMatch::Substring);
+    }
+
+    let text: Vec<char> = text.chars().collect();
+    if text.len() < TYPO_MIN_LENGTH {
+        return None;
+    }
+    // The start of a word with one character more or less than the text, so a missing
+    // or extra letter counts as one typo as well.
+    let typo = word_starts.iter().any(|start| {
+        let word: Vec<char> = place[*start..].chars().collect();
+        (text.len() - 1..=text.len() + 1)
+            .filter(|length| *length <= word.len())
+            .any(|length| edit_distance(&text, &word[..length]) <= 1)
+    });
+
+    typo.then_some(PlaceMatch::Typo)
+}
+
+impl PostcodeIndex {
+    pub fn new(postcodes: &[PostcodeInfo]) -> Self {
+        let mut entries: Vec<Entry> = postcodes
+            .iter()
+            .map(|info| Entry {
+                zipcode: info.zipcode.to_string(),
+                place: normalize(&info.place),
+                info: info.clone(),
+            })
+            .collect();
+        entries.sort_by(|a, b| a.zipcode.cmp(&b.zipcode).then(a.place.cmp(&b.place)));
+
+        PostcodeIndex { entries }
+    }
+
+    // The entries whose zipcode starts with `prefix`.
+    fn with_prefix(&self, prefix: &str) -> &[Entry] {
+        let start = self
+            .entries
+            .partition_point(|entry| entry.zipcode.as_str() < prefix);
+        let end = start
+            + self.entries[start..].partition_point(|entry| entry.zipcode.starts_with(prefix));
+
+        &self.entries[start..end]
+    }
+
+    // The best `limit` postcodes for `query`. Digits are matched against the start of the
+    // zipcode, anything else against the place name, and both have to match if given.
+    // Exact matches come first, then prefixes, then matches anywhere in the place name.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<&PostcodeInfo> {
+        let (digits, words): (Vec<&str>, Vec<&str>) = query
+            .split_whitespace()
+            .partition(|word| word.chars().all(|c| c.is_ascii_digit()));
+        let digits = digits.concat();
+        let text = normalize(&words.join(" "));
+
+        if digits.is_empty() && text.is_empty() {
+            return Vec::new();
+        }
+
+        let candidates = if digits.is_empty() {
+            &self.entries[..]
+        } else {
+            self.with_prefix(&digits)
+        };
+
+        let mut scored: Vec<(u8, Option<PlaceMatch>, &Entry)> = candidates
+            .iter()
+            .filter_map(|entry| {
+                let place = match text.is_empty() {
+                    true => None,
+                    false => Some(match_place(&entry.place, &text)?),
+                };
+                let zipcode = match digits.is_empty() {
+                    true => 0,
+                    false if entry.zipcode == digits => 2,
+                    false => 1,
+                };
+
+                Some((zipcode, place, entry))
+            })
+            .collect();
+
+        // Stable, so equal scores keep the zipcode order.
+        scored.sort_by_key(|(zipcode, place, _)| Reverse((*place, *zipcode)));
+
+        scored
+            .into_iter()
+            .take(limit)
+            .map(|(_, _, entry)| &entry.info)
+            .collect()
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::fs;
+use std::time::Instant;
+
+use crate::data::{PostalCode, Postcode, PostcodeGroup};
+use crate::map::Map;
+use crate::synthetic::{self, position, Rng};
+
+// Resident memory of the process in KiB, where the platform exposes it.
+fn resident_kib() -> Option<u64> {
+    fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+// Builds a map of synthetic providers and postcodes and times index builds, searches
+// and updates.
+pub fn run(providers: u32, postcodes: u32, updates: u32) {
+    let mut rng = Rng(0x2545f4914f6cdd1d);
+
+    let groups = PostcodeGroup::all();
+    // There are no more than 100000 postal codes.
+    let postcode_data: HashMap<PostalCode, Postcode> = (0..postcodes)
+        .map_while(|number| Some((number, PostalCode::from_number(number)?)))
+        .map(|(number, postcode)| {
+            let (lon, lat) = position(&mut rng);
+            let group = groups[number as usize % groups.len()].clone();
+            (
+                postcode,
+                Postcode {
+                    postcode,
+                    lon,
+                    lat,
+                    postcode_extension_distance_group: group,
+                },
+            )
+        })
+        .collect();
+
+    let (provider_data, quality) = synthetic::providers(&mut rng, providers, |rng| {
+        (position(rng), rng.range(5000.0, 80000.0) as u64)
+    });
+
+    let memory_before = resident_kib();
+    let start = Instant::now();
+    let mut map = Map::new(postcode_data, quality, provider_data);
+    let build = start.elapsed();
+    let memory_after = resident_kib();
+
+    let start = Instant::now();
+    let counts = map.provider_counts();
+    let search = start.elapsed();
+    let matches: usize = counts.iter().map(|(_, _, count)| count).sum();
+
+    let start = Instant::now();
+    for _ in 0..updates {
+        let id = 1 + (rng.next() % providers as u64) as u32;
+        let distance = rng.range(5000.0, 80000.0) as u64;
+        map.update_service_provider(id, Some(distance), None, None, None)
+            .expect("The in-memory store does not fail.");
+    }
+    let update = start.elapsed();
+
+    println!("{providers} providers, {postcodes} postcodes, {updates} updates");
+    println!("build:  {:.1} ms", build.as_secs_f64() * 1000.0);
+    match (memory_before, memory_after) {
+        (Some(before), Some(after)) => println!(
+            "memory: {:.1} MiB resident for the map",
+            after.saturating_sub(before) as f64 / 1024.0
+        ),
+        _ => println!("memory: not available on this platform"),
+    }
+    println!(
+        "search: {:.1} µs per postcode ({matches} matches)",
+        search.as_secs_f64() * 1e6 / postcodes.max(1) as f64
+    );
+    println!(
+        "update: {:.1} µs per update",
+        update.as_secs_f64() * 1e6 / updates.max(1) as f64
+    );
+}
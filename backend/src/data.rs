@@ -1,34 +1,209 @@
-use serde_json;
 use std::error::Error;
-use std::{collections::HashMap, fs};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::{collections::HashMap, fmt, fs};
 
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+
+use crate::geo::Geometry;
+use crate::import::{self, Format};
+use crate::validation::{
+    validate_position, Invalid, LoadError, Record, ValidationReport, MAX_DRIVING_DISTANCE,
+};
 use std::str::FromStr;
 
-#[derive(Debug, Clone)]
-pub enum PostcodeGroup {
-    GroupA,
-    GroupB,
-    GroupC,
+// Extension distance group of a postcode. Groups are configured along with the datasets,
+// see `PostcodeGroup::configure`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PostcodeGroup {
+    name: Arc<str>,
+    extension: u64,
+}
+
+fn default_groups() -> Vec<PostcodeGroup> {
+    [("group_a", 0), ("group_b", 2000), ("group_c", 5000)]
+        .into_iter()
+        .map(|(name, extension)| PostcodeGroup::new(name, extension))
+        .collect()
+}
+
+fn groups() -> &'static RwLock<Vec<PostcodeGroup>> {
+    static GROUPS: OnceLock<RwLock<Vec<PostcodeGroup>>> = OnceLock::new();
+    GROUPS.get_or_init(|| RwLock::new(default_groups()))
+}
+
+impl PostcodeGroup {
+    pub fn new(name: &str, extension: u64) -> Self {
+        PostcodeGroup {
+            name: name.into(),
+            extension,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Metres added to the driving distance of providers for postcodes in this group.
+    pub fn extension(&self) -> u64 {
+        self.extension
+    }
+
+    // Replaces the known groups. Postcodes are read with the groups known at the time, and
+    // a map indexes its providers for the largest extension when it is built.
+    pub fn configure(configured: Vec<PostcodeGroup>) {
+        *groups().write().unwrap() = configured;
+    }
+
+    // Reads a JSON object mapping group names to their extension in metres. The groups
+    // in use are only replaced if the whole file is valid.
+    pub fn configure_from_file(path: &Path) -> Result<(), LoadError> {
+        let dataset = "postcode groups";
+        let configured: HashMap<String, u64> = import::read_config(path, dataset)?;
+
+        if configured.is_empty() {
+            return Err(LoadError::Invalid {
+                dataset,
+                id: path.display().to_string(),
+                reason: Invalid::NoGroups,
+            });
+        }
+        for (name, extension) in &configured {
+            if *extension > MAX_DRIVING_DISTANCE {
+                return Err(LoadError::Invalid {
+                    dataset,
+                    id: format!("`{name}`"),
+                    reason: Invalid::ExtensionOutOfRange(*extension),
+                });
+            }
+        }
+
+        let mut configured: Vec<PostcodeGroup> = configured
+            .iter()
+            .map(|(name, extension)| PostcodeGroup::new(name, *extension))
+            .collect();
+        configured.sort_by_key(|group| (group.extension, group.name.clone()));
+
+        PostcodeGroup::configure(configured);
+        Ok(())
+    }
+
+    // All configured groups, smallest extension first.
+    pub fn all() -> Vec<PostcodeGroup> {
+        groups().read().unwrap().clone()
+    }
+
+    // The largest extension of any group, which the spatial index makes room for.
+    pub fn max_extension() -> u64 {
+        groups()
+            .read()
+            .unwrap()
+            .iter()
+            .map(PostcodeGroup::extension)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for PostcodeGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        groups()
+            .read()
+            .unwrap()
+            .iter()
+            .find(|group| group.name() == s)
+            .cloned()
+            .ok_or_else(|| format!("Unknown postcode extension distance group `{s}`."))
+    }
+}
+
+impl Serialize for PostcodeGroup {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+// German postal code. Kept as a number, but always five digits when written, so
+// "01067" doesn't turn into 1067.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PostalCode(u32);
+
+impl PostalCode {
+    pub fn from_number(number: u32) -> Option<Self> {
+        (number <= 99999).then_some(PostalCode(number))
+    }
+}
+
+impl fmt::Display for PostalCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:05}", self.0)
+    }
+}
+
+impl FromStr for PostalCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 5 || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!(
+                "`{s}` is not a valid postal code, expected five digits."
+            ));
+        }
+
+        Ok(PostalCode(s.parse().unwrap()))
+    }
+}
+
+impl Serialize for PostalCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Only strings, a number has already lost its leading zeros. Logs written with numbers
+// are read through `store::numeric_postcode`.
+impl<'de> Deserialize<'de> for PostalCode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = PostalCode;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a postal code of five digits")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<PostalCode, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(Visitor)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct PostcodeInfo {
-    #[serde(deserialize_with = "from_str_u32")]
-    pub zipcode: u32,
+    pub zipcode: PostalCode,
     // Friendly display name (e.g. "Garching bei München")
     pub place: String,
     #[serde(deserialize_with = "from_str_f32")]
     pub latitude: f32,
     #[serde(deserialize_with = "from_str_f32")]
     pub longitude: f32,
+    // Federal state, used to group postcodes into regions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
     // There are other attributes we might want to use later, but don't need yet
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Postcode {
-    #[serde(deserialize_with = "from_str_u32")]
-    pub postcode: u32,
+    pub postcode: PostalCode,
 
     #[serde(deserialize_with = "to_radians")]
     pub lon: f64,
@@ -63,17 +238,128 @@ pub struct ServiceProvider {
     pub lat: f64,
 
     pub max_driving_distance: u64,
+
+    // Minutes, replaces the driving distance when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_travel_time: Option<u64>,
+
+    // GeoJSON (multi)polygon served instead of the driving distance or travel time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_area: Option<Geometry>,
+
+    // GeoJSON (multi)polygons never served, like islands or city centres
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_areas: Vec<Geometry>,
+}
+
+// Address of a service provider as accepted by the API, coordinates in degrees.
+// Coordinates left out are geocoded before the address is stored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Address {
+    pub city: String,
+    pub street: String,
+    pub house_number: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub postcode: Option<PostalCode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lon: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lat: Option<f64>,
+}
+
+impl Address {
+    pub fn position(&self) -> Option<(f64, f64)> {
+        self.lon.zip(self.lat)
+    }
+}
+
+// A known address of the offline gazetteer, coordinates in degrees.
+#[derive(Deserialize, Debug, Clone)]
+pub struct GazetteerEntry {
+    pub city: String,
+    pub street: String,
+    pub house_number: String,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+// A service provider to onboard, together with its profile scores.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NewServiceProvider {
+    pub first_name: String,
+    pub last_name: String,
+    #[serde(flatten)]
+    pub address: Address,
+    pub max_driving_distance: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_travel_time: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_area: Option<Geometry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_areas: Vec<Geometry>,
+    pub profile_picture_score: f64,
+    pub profile_description_score: f64,
+}
+
+impl NewServiceProvider {
+    pub fn into_parts(self, id: u32) -> (ServiceProvider, QualityFactor) {
+        let quality = QualityFactor {
+            profile_id: id,
+            profile_picture_score: self.profile_picture_score,
+            profile_description_score: self.profile_description_score,
+        };
+
+        let mut provider = ServiceProvider {
+            id,
+            first_name: self.first_name,
+            last_name: self.last_name,
+            city: String::new(),
+            street: String::new(),
+            house_number: String::new(),
+            lon: 0.0,
+            lat: 0.0,
+            max_driving_distance: self.max_driving_distance,
+            max_travel_time: self.max_travel_time.filter(|minutes| *minutes > 0),
+            service_area: self.service_area,
+            excluded_areas: self.excluded_areas,
+        };
+        provider.set_address(self.address);
+
+        (provider, quality)
+    }
+}
+
+impl ServiceProvider {
+    pub fn set_address(&mut self, address: Address) {
+        if let Some((lon, lat)) = address.position() {
+            self.lon = lon.to_radians();
+            self.lat = lat.to_radians();
+        }
+
+        self.city = address.city;
+        self.street = address.street;
+        self.house_number = address.house_number;
+    }
 }
 
 #[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct ServiceProviderView {
     pub id: u32,
     pub name: String,
-    pub rankingScore: f64,
+    pub ranking_score: f64,
 }
 
 impl Eq for PostcodeInfo {}
 
+impl PartialOrd for PostcodeInfo {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Ord for PostcodeInfo {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.zipcode.cmp(&other.zipcode)
@@ -86,62 +372,175 @@ impl std::hash::Hash for PostcodeInfo {
     }
 }
 
-//Move initial data into binary for faster startup times.
-const INITIAL_POSTCODE_DATA: &'static str = include_str!("../data/postcode.json");
-const INITIAL_SERVICE_PROVIDER_DATA: &'static str =
-    include_str!("../data/service_provider_profile.json");
-const INITIAL_QUALITY_DATA: &'static str = include_str!("../data/quality_factor_score.json");
+//Optionally move initial data into binary as a fallback when no data file is configured.
+#[cfg(feature = "embedded-data")]
+macro_rules! embedded {
+    ($path:literal) => {
+        Some(include_str!($path))
+    };
+}
+
+#[cfg(not(feature = "embedded-data"))]
+macro_rules! embedded {
+    ($path:literal) => {
+        None
+    };
+}
+
+const INITIAL_POSTCODE_DATA: Option<&str> = embedded!("../data/postcode.json");
+const INITIAL_SERVICE_PROVIDER_DATA: Option<&str> =
+    embedded!("../data/service_provider_profile.json");
+const INITIAL_QUALITY_DATA: Option<&str> = embedded!("../data/quality_factor_score.json");
 
 //File parsing functions
 
-pub fn postcode_from_file() -> Result<HashMap<u32, Postcode>, String> {
-    let res = serde_json::from_str::<Vec<Postcode>>(INITIAL_POSTCODE_DATA);
+// Opens a dataset at `path`, falling back to the embedded copy if the binary has one.
+fn open_dataset(
+    path: Option<&Path>,
+    embedded: Option<&'static str>,
+    dataset: &'static str,
+) -> Result<(Box<dyn BufRead>, Format), LoadError> {
+    let io_error = |source| LoadError::Io {
+        dataset,
+        path: path.map(Path::to_path_buf).unwrap_or_default(),
+        source,
+    };
+
+    let mut reader: Box<dyn BufRead> = match (path, embedded) {
+        (Some(path), _) => Box::new(BufReader::new(fs::File::open(path).map_err(io_error)?)),
+        (None, Some(data)) => Box::new(data.as_bytes()),
+        (None, None) => return Err(LoadError::Missing { dataset }),
+    };
+
+    let head = reader.fill_buf().map_err(io_error)?;
+    let format = Format::detect(path, head);
+
+    Ok((reader, format))
+}
 
-    if let Ok(service_provider) = res {
-        Ok(service_provider
-            .iter()
-            .map(|x| (x.postcode, (*x).to_owned()))
-            .collect())
-    } else if let Err(e) = res {
-        Err(format!("{e}").to_string())
-    } else {
-        Err("Failed to parse postcode. Unknown error.".to_string())
-    }
+// Parses and validates every record of a dataset, keyed by id. Rejected records either
+// abort the load or end up in `report`, depending on its mode.
+fn load_dataset<T: DeserializeOwned + Record>(
+    path: Option<&Path>,
+    embedded: Option<&'static str>,
+    report: &mut ValidationReport,
+) -> Result<HashMap<T::Id, T>, LoadError> {
+    let dataset = T::DATASET;
+    let (reader, format) = open_dataset(path, embedded, dataset)?;
+    let mut records = HashMap::new();
+
+    import::read_records::<T, _, _>(reader, format, |record| {
+        let record = match record {
+            Ok(record) => record,
+            Err(error) => return report.reject(LoadError::Record { dataset, error }),
+        };
+
+        let id = record.id();
+        let valid = record.validate().and_then(|_| {
+            if records.contains_key(&id) {
+                Err(Invalid::DuplicateId)
+            } else {
+                Ok(())
+            }
+        });
+
+        match valid {
+            Ok(()) => {
+                records.insert(id, record);
+                Ok(())
+            }
+            Err(reason) => report.reject(LoadError::Invalid {
+                dataset,
+                id: id.to_string(),
+                reason,
+            }),
+        }
+    })?;
+
+    Ok(records)
 }
 
-pub fn quality_from_file() -> Result<HashMap<u32, QualityFactor>, String> {
-    let res = serde_json::from_str::<Vec<QualityFactor>>(INITIAL_QUALITY_DATA);
+pub fn postcode_from_file(
+    path: Option<&Path>,
+    report: &mut ValidationReport,
+) -> Result<HashMap<PostalCode, Postcode>, LoadError> {
+    load_dataset(path, INITIAL_POSTCODE_DATA, report)
+}
 
-    if let Ok(service_provider) = res {
-        Ok(service_provider
-            .iter()
-            .map(|x| (x.profile_id, (*x).to_owned()))
-            .collect())
-    } else if let Err(e) = res {
-        Err(format!("{e}").to_string())
-    } else {
-        Err("Failed to parse quality factor. Unknown error.".to_string())
-    }
+pub fn quality_from_file(
+    path: Option<&Path>,
+    report: &mut ValidationReport,
+) -> Result<HashMap<u32, QualityFactor>, LoadError> {
+    load_dataset(path, INITIAL_QUALITY_DATA, report)
 }
 
-pub fn provider_from_file() -> Result<HashMap<u32, ServiceProvider>, String> {
-    let res = serde_json::from_str::<Vec<ServiceProvider>>(INITIAL_SERVICE_PROVIDER_DATA);
+pub fn provider_from_file(
+    path: Option<&Path>,
+    report: &mut ValidationReport,
+) -> Result<HashMap<u32, ServiceProvider>, LoadError> {
+    load_dataset(path, INITIAL_SERVICE_PROVIDER_DATA, report)
+}
 
-    if let Ok(service_providers) =
-        serde_json::from_str::<Vec<ServiceProvider>>(INITIAL_SERVICE_PROVIDER_DATA)
-    {
-        Ok(service_providers
-            .iter()
-            .map(|x| (x.id, (*x).to_owned()))
-            .collect())
-    } else if let Err(e) = res {
-        Err(format!("{e}").to_string())
-    } else {
-        Err("Failed to load service providers. Unknown error.".to_string())
+// Reads the address gazetteer used for geocoding. Entries have no id of their own, so
+// rejected ones are reported by their position in the file.
+pub fn gazetteer_from_file(
+    path: &Path,
+    report: &mut ValidationReport,
+) -> Result<Vec<GazetteerEntry>, LoadError> {
+    let dataset = "gazetteer";
+    let (reader, format) = open_dataset(Some(path), None, dataset)?;
+    let mut entries = Vec::new();
+
+    let mut index = 0;
+    import::read_records::<GazetteerEntry, _, _>(reader, format, |entry| {
+        index += 1;
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => return report.reject(LoadError::Record { dataset, error }),
+        };
+
+        match validate_position(entry.lon.to_radians(), entry.lat.to_radians()) {
+            Ok(()) => {
+                entries.push(entry);
+                Ok(())
+            }
+            Err(reason) => report.reject(LoadError::Invalid {
+                dataset,
+                id: index.to_string(),
+                reason,
+            }),
+        }
+    })?;
+
+    Ok(entries)
+}
+
+// Providers can only be ranked with a quality factor, so those without one are dropped.
+pub fn drop_unrated_providers(
+    service_providers: &mut HashMap<u32, ServiceProvider>,
+    quality_factor: &HashMap<u32, QualityFactor>,
+    report: &mut ValidationReport,
+) -> Result<(), LoadError> {
+    let mut unrated: Vec<u32> = service_providers
+        .keys()
+        .filter(|id| !quality_factor.contains_key(id))
+        .copied()
+        .collect();
+    unrated.sort();
+
+    for id in unrated {
+        report.reject(LoadError::Invalid {
+            dataset: ServiceProvider::DATASET,
+            id: id.to_string(),
+            reason: Invalid::MissingQualityFactor,
+        })?;
+        service_providers.remove(&id);
     }
+
+    Ok(())
 }
 
-pub fn postcode_info_from_file(path: &str) -> Result<Vec<PostcodeInfo>, Box<dyn Error>> {
+pub fn postcode_info_from_file(path: &Path) -> Result<Vec<PostcodeInfo>, Box<dyn Error>> {
     let file_content = fs::read_to_string(path)?;
     let postcodes: Vec<PostcodeInfo> = serde_json::from_str(&file_content)?;
     Ok(postcodes)
@@ -149,29 +548,12 @@ pub fn postcode_info_from_file(path: &str) -> Result<Vec<PostcodeInfo>, Box<dyn
 
 // Serializing functions
 
-fn from_str_u32<'de, D>(deserializer: D) -> Result<u32, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    u32::from_str(&s).map_err(serde::de::Error::custom)
-}
-
 fn from_str_group<'de, D>(deserializer: D) -> Result<PostcodeGroup, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     let s = String::deserialize(deserializer)?;
-
-    // either group_a, group_b, group_c
-    match s.as_str() {
-        "group_a" => Ok(PostcodeGroup::GroupA),
-        "group_b" => Ok(PostcodeGroup::GroupB),
-        "group_c" => Ok(PostcodeGroup::GroupC),
-        _ => Err(serde::de::Error::custom(
-            "Invalid postcode extension distance group.",
-        )),
-    }
+    s.parse().map_err(serde::de::Error::custom)
 }
 
 fn from_str_f32<'de, D>(deserializer: D) -> Result<f32, D::Error>
@@ -0,0 +1,162 @@
+use std::fmt;
+
+use actix_web::http::header::{self, ContentType};
+use actix_web::http::StatusCode;
+use actix_web::{error::JsonPayloadError, HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+
+use crate::data::PostalCode;
+use crate::geo::Geometry;
+use crate::validation::{validate_area, MAX_DRIVING_DISTANCE, MAX_TRAVEL_TIME};
+
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Unprocessable(String),
+    Internal(String),
+}
+
+// Problem details body (RFC 7807) sent with every error response.
+#[derive(Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    title: &'a str,
+    status: u16,
+    detail: &'a str,
+}
+
+impl ApiError {
+    pub fn craftman_not_found(id: u32) -> Self {
+        ApiError::NotFound(format!("There is no craftsman with id {id}."))
+    }
+
+    fn detail(&self) -> &str {
+        match self {
+            ApiError::BadRequest(detail)
+            | ApiError::Unauthorized(detail)
+            | ApiError::Forbidden(detail)
+            | ApiError::NotFound(detail)
+            | ApiError::Unprocessable(detail)
+            | ApiError::Internal(detail) => detail,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.detail())
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let problem = Problem {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail: self.detail(),
+        };
+
+        let mut response = HttpResponse::build(status);
+        response.insert_header(ContentType("application/problem+json".parse().unwrap()));
+        if let ApiError::Unauthorized(_) = self {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+
+        response.body(serde_json::to_string(&problem).unwrap())
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(error: std::io::Error) -> Self {
+        ApiError::Internal(error.to_string())
+    }
+}
+
+impl From<actix_web::error::BlockingError> for ApiError {
+    fn from(error: actix_web::error::BlockingError) -> Self {
+        ApiError::Internal(error.to_string())
+    }
+}
+
+// Bodies that are valid JSON but don't fit the request type are unprocessable,
+// anything else is a bad request.
+pub fn json_error(error: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match &error {
+        JsonPayloadError::Deserialize(e) if e.is_data() => {
+            ApiError::Unprocessable(e.to_string()).into()
+        }
+        _ => ApiError::BadRequest(error.to_string()).into(),
+    }
+}
+
+pub fn parse_id(value: &str) -> Result<u32, ApiError> {
+    value
+        .parse()
+        .map_err(|_| ApiError::BadRequest(format!("`{value}` is not a valid craftsman id.")))
+}
+
+pub fn parse_postcode(value: &str) -> Result<PostalCode, ApiError> {
+    value.parse().map_err(ApiError::BadRequest)
+}
+
+// Checks that service areas and excluded zones are closed polygons.
+pub fn check_areas(service_area: Option<&Geometry>, excluded: &[Geometry]) -> Result<(), ApiError> {
+    let areas = service_area
+        .map(|area| ("serviceArea", area))
+        .into_iter()
+        .chain(excluded.iter().map(|area| ("excludedAreas", area)));
+
+    for (name, area) in areas {
+        validate_area(area)
+            .map_err(|reason| ApiError::Unprocessable(format!("`{name}`: {reason}.")))?;
+    }
+
+    Ok(())
+}
+
+// Checks that a profile score lies within 0..=1.
+pub fn check_score(name: &str, score: Option<f64>) -> Result<(), ApiError> {
+    match score {
+        Some(score) if !(0.0..=1.0).contains(&score) => Err(ApiError::Unprocessable(format!(
+            "`{name}` must be between 0 and 1, got {score}."
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// Checks that a driving distance is at most `MAX_DRIVING_DISTANCE` metres.
+pub fn check_distance(name: &str, distance: Option<u64>) -> Result<(), ApiError> {
+    match distance {
+        Some(distance) if distance > MAX_DRIVING_DISTANCE => Err(ApiError::Unprocessable(format!(
+            "`{name}` must be at most {MAX_DRIVING_DISTANCE} metres, got {distance}."
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// Checks that a travel time is at most `MAX_TRAVEL_TIME` minutes. 0 removes it.
+pub fn check_travel_time(name: &str, minutes: Option<u64>) -> Result<(), ApiError> {
+    match minutes {
+        Some(minutes) if minutes > MAX_TRAVEL_TIME => Err(ApiError::Unprocessable(format!(
+            "`{name}` must be at most {MAX_TRAVEL_TIME} minutes, got {minutes}."
+        ))),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,102 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+
+use crate::data::{PostalCode, PostcodeGroup, PostcodeInfo};
+use crate::map::Map;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GapReport {
+    pub min_providers: usize,
+    pub postcodes: usize,
+    // Postcodes with fewer than `min_providers` providers
+    pub underserved: usize,
+    // Postcodes without any provider
+    pub unserved: usize,
+    pub regions: Vec<RegionGaps>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RegionGaps {
+    pub region: String,
+    pub group: PostcodeGroup,
+    pub postcodes: usize,
+    pub underserved: usize,
+    pub unserved: usize,
+    pub gaps: Vec<Gap>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Gap {
+    pub postcode: PostalCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub place: Option<String>,
+    pub providers: usize,
+}
+
+// The federal state of a postcode, or its leading digit if the state is unknown.
+fn region(postcode: PostalCode, info: Option<&PostcodeInfo>) -> String {
+    info.and_then(|info| info.state.clone())
+        .filter(|state| !state.is_empty())
+        .unwrap_or_else(|| format!("{}xxxx", &postcode.to_string()[..1]))
+}
+
+// Finds the postcodes served by fewer than `min_providers` providers, grouped by region
+// and extension group. Only groups with gaps are listed, the largest first.
+pub fn coverage_gaps(
+    map: &Map,
+    postcode_info: &HashMap<PostalCode, PostcodeInfo>,
+    min_providers: usize,
+) -> GapReport {
+    let counts = map.provider_counts();
+    let mut regions: BTreeMap<(String, String), RegionGaps> = BTreeMap::new();
+
+    for (postcode, group, providers) in &counts {
+        let info = postcode_info.get(postcode);
+        let region = region(*postcode, info);
+
+        let entry = regions
+            .entry((region.clone(), group.name().to_string()))
+            .or_insert_with(|| RegionGaps {
+                region,
+                group: group.clone(),
+                postcodes: 0,
+                underserved: 0,
+                unserved: 0,
+                gaps: Vec::new(),
+            });
+
+        entry.postcodes += 1;
+        if *providers < min_providers {
+            entry.underserved += 1;
+            entry.gaps.push(Gap {
+                postcode: *postcode,
+                place: info.map(|info| info.place.clone()),
+                providers: *providers,
+            });
+        }
+        if *providers == 0 {
+            entry.unserved += 1;
+        }
+    }
+
+    let mut regions: Vec<RegionGaps> = regions
+        .into_values()
+        .filter(|region| region.underserved > 0)
+        .collect();
+    for region in &mut regions {
+        region.gaps.sort_by_key(|gap| (gap.providers, gap.postcode));
+    }
+    regions.sort_by_key(|region| Reverse(region.underserved));
+
+    GapReport {
+        min_providers,
+        postcodes: counts.len(),
+        underserved: regions.iter().map(|region| region.underserved).sum(),
+        unserved: regions.iter().map(|region| region.unserved).sum(),
+        regions,
+    }
+}
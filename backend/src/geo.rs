@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+
+use crate::map::EARTH_RADIUS;
+
+// An outer ring followed by its holes, as in GeoJSON.
+pub type Polygon = Vec<Vec<[f64; 2]>>;
+
+// GeoJSON geometry, positions are [lon, lat] in degrees.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum Geometry {
+    Polygon { coordinates: Polygon },
+    MultiPolygon { coordinates: Vec<Polygon> },
+    MultiPoint { coordinates: Vec<[f64; 2]> },
+}
+
+impl Geometry {
+    // The polygons making up the geometry, none for points.
+    pub fn polygons(&self) -> Vec<Polygon> {
+        match self {
+            Geometry::Polygon { coordinates } => vec![coordinates.clone()],
+            Geometry::MultiPolygon { coordinates } => coordinates.clone(),
+            Geometry::MultiPoint { .. } => Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Feature<P: Serialize> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    geometry: Option<Geometry>,
+    properties: P,
+}
+
+impl<P: Serialize> Feature<P> {
+    pub fn new(geometry: Option<Geometry>, properties: P) -> Self {
+        Feature {
+            kind: "Feature",
+            geometry,
+            properties,
+        }
+    }
+}
+
+fn cross(o: [f64; 2], a: [f64; 2], b: [f64; 2]) -> f64 {
+    (a[0] - o[0]) * (b[1] - o[1]) - (a[1] - o[1]) * (b[0] - o[0])
+}
+
+// Convex hull in counter-clockwise order (Andrew's monotone chain), without repeating
+// the first point. Treats the coordinates as planar, which is fine for regional areas.
+pub fn convex_hull(points: &[[f64; 2]]) -> Vec<[f64; 2]> {
+    let mut points = points.to_vec();
+    points.sort_by(|a, b| a[0].total_cmp(&b[0]).then(a[1].total_cmp(&b[1])));
+    points.dedup();
+
+    if points.len() < 3 {
+        return points;
+    }
+
+    let mut hull: Vec<[f64; 2]> = Vec::with_capacity(points.len() * 2);
+    for pass in [points.clone(), points.into_iter().rev().collect()] {
+        let start = hull.len();
+        for point in pass {
+            while hull.len() >= start + 2
+                && cross(hull[hull.len() - 2], hull[hull.len() - 1], point) <= 0.0
+            {
+                hull.pop();
+            }
+            hull.push(point);
+        }
+        // The last point of each half is the first of the other one.
+        hull.pop();
+    }
+
+    hull
+}
+
+// Whether `point` lies inside `ring` (even-odd rule), planar like `convex_hull`. The ring
+// may or may not repeat its first point.
+fn contains(ring: &[[f64; 2]], point: [f64; 2]) -> bool {
+    let mut inside = false;
+
+    for (i, a) in ring.iter().enumerate() {
+        let b = ring[(i + ring.len() - 1) % ring.len()];
+        if (a[1] > point[1]) != (b[1] > point[1])
+            && point[0] < (b[0] - a[0]) * (point[1] - a[1]) / (b[1] - a[1]) + a[0]
+        {
+            inside = !inside;
+        }
+    }
+
+    inside
+}
+
+// Metres from `point` to the closest edge of `ring`, measured in a flat projection
+// around `point`.
+fn distance_to_ring(ring: &[[f64; 2]], point: [f64; 2]) -> f64 {
+    let scale = point[1].to_radians().cos();
+    let project = |p: [f64; 2]| {
+        [
+            (p[0] - point[0]).to_radians() * scale * EARTH_RADIUS,
+            (p[1] - point[1]).to_radians() * EARTH_RADIUS,
+        ]
+    };
+
+    ring.iter()
+        .enumerate()
+        .map(|(i, a)| {
+            let (a, b) = (project(*a), project(ring[(i + 1) % ring.len()]));
+            let edge = [b[0] - a[0], b[1] - a[1]];
+            let length_2 = edge[0] * edge[0] + edge[1] * edge[1];
+            let t = if length_2 > 0.0 {
+                (-(a[0] * edge[0] + a[1] * edge[1]) / length_2).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+
+            (a[0] + t * edge[0]).hypot(a[1] + t * edge[1])
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+// Whether `point` lies inside `polygon` but not in one of its holes.
+pub fn polygon_contains(polygon: &Polygon, point: [f64; 2]) -> bool {
+    polygon.iter().filter(|ring| contains(ring, point)).count() % 2 == 1
+}
+
+// Metres from `point` to the closest edge of `polygon`, holes included.
+pub fn distance_to_polygon(polygon: &Polygon, point: [f64; 2]) -> f64 {
+    polygon
+        .iter()
+        .map(|ring| distance_to_ring(ring, point))
+        .fold(f64::INFINITY, f64::min)
+}
+
+// [[min lon, min lat], [max lon, max lat]] of all positions of `polygons`.
+pub fn bounds(polygons: &[Polygon]) -> Option<[[f64; 2]; 2]> {
+    let mut positions = polygons.iter().flatten().flatten();
+    let first = *positions.next()?;
+
+    Some(positions.fold([first, first], |[min, max], p| {
+        [
+            [min[0].min(p[0]), min[1].min(p[1])],
+            [max[0].max(p[0]), max[1].max(p[1])],
+        ]
+    }))
+}
+
+// The hull of `points` as a polygon, or the points themselves if they don't span an area.
+pub fn hull_geometry(points: &[[f64; 2]]) -> Option<Geometry> {
+    let mut hull = convex_hull(points);
+
+    match hull.len() {
+        0 => None,
+        1 | 2 => Some(Geometry::MultiPoint { coordinates: hull }),
+        _ => {
+            hull.push(hull[0]);
+            Some(Geometry::Polygon {
+                coordinates: vec![hull],
+            })
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use crate::data::{Address, GazetteerEntry, PostalCode, PostcodeInfo};
+
+// Resolves addresses to coordinates (lon, lat in degrees) without an external service.
+// Tries the exact address first, then the centre of the street, the postcode and
+// finally the city.
+#[derive(Default)]
+pub struct Geocoder {
+    addresses: HashMap<(String, String, String), (f64, f64)>,
+    streets: HashMap<(String, String), (f64, f64)>,
+    postcodes: HashMap<PostalCode, (f64, f64)>,
+    cities: HashMap<String, (f64, f64)>,
+}
+
+// Running sum of positions to take the mean of.
+#[derive(Default)]
+struct Centroid {
+    lon: f64,
+    lat: f64,
+    count: usize,
+}
+
+impl Centroid {
+    fn add(&mut self, (lon, lat): (f64, f64)) {
+        self.lon += lon;
+        self.lat += lat;
+        self.count += 1;
+    }
+
+    fn position(&self) -> (f64, f64) {
+        (self.lon / self.count as f64, self.lat / self.count as f64)
+    }
+}
+
+fn centroids<K: std::hash::Hash + Eq>(
+    positions: impl Iterator<Item = (K, (f64, f64))>,
+) -> HashMap<K, (f64, f64)> {
+    let mut sums: HashMap<K, Centroid> = HashMap::new();
+    for (key, position) in positions {
+        sums.entry(key).or_default().add(position);
+    }
+
+    sums.into_iter()
+        .map(|(key, sum)| (key, sum.position()))
+        .collect()
+}
+
+// Lower case, single spaces and "straße", "strasse" and "str." all spelled "str".
+fn normalize(value: &str) -> String {
+    value
+        .to_lowercase()
+        .replace('ß', "ss")
+        .replace("strasse", "str")
+        .replace("str.", "str")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Geocoder {
+    pub fn new(gazetteer: &[GazetteerEntry], zipcodes: &[PostcodeInfo]) -> Self {
+        let addresses = gazetteer
+            .iter()
+            .map(|entry| {
+                (
+                    (
+                        normalize(&entry.city),
+                        normalize(&entry.street),
+                        normalize(&entry.house_number),
+                    ),
+                    (entry.lon, entry.lat),
+                )
+            })
+            .collect();
+
+        let streets = centroids(gazetteer.iter().map(|entry| {
+            (
+                (normalize(&entry.city), normalize(&entry.street)),
+                (entry.lon, entry.lat),
+            )
+        }));
+
+        let postcodes = zipcodes
+            .iter()
+            .map(|info| (info.zipcode, (info.longitude as f64, info.latitude as f64)))
+            .collect();
+
+        let cities = centroids(zipcodes.iter().map(|info| {
+            (
+                normalize(&info.place),
+                (info.longitude as f64, info.latitude as f64),
+            )
+        }));
+
+        Geocoder {
+            addresses,
+            streets,
+            postcodes,
+            cities,
+        }
+    }
+
+    pub fn geocode(&self, address: &Address) -> Option<(f64, f64)> {
+        let city = normalize(&address.city);
+        let street = normalize(&address.street);
+        let house_number = normalize(&address.house_number);
+
+        self.addresses
+            .get(&(city.clone(), street.clone(), house_number))
+            .or_else(|| self.streets.get(&(city.clone(), street)))
+            .or_else(|| {
+                address
+                    .postcode
+                    .and_then(|postcode| self.postcodes.get(&postcode))
+            })
+            .or_else(|| self.cities.get(&city))
+            .copied()
+    }
+
+    // Fills in the coordinates of `address` unless it already has them.
+    // Returns false if the address could not be found.
+    pub fn locate(&self, address: &mut Address) -> bool {
+        if address.position().is_some() {
+            return true;
+        }
+
+        match self.geocode(address) {
+            Some((lon, lat)) => {
+                address.lon = Some(lon);
+                address.lat = Some(lat);
+                true
+            }
+            None => false,
+        }
+    }
+}
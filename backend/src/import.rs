@@ -0,0 +1,315 @@
+use std::cell::Cell;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Read};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::rc::Rc;
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use crate::validation::LoadError;
+
+// Supported dataset encodings. Json is a single array, Ndjson one object per line
+// and Csv a table with a header row naming the fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Ndjson,
+    Csv,
+}
+
+impl Format {
+    // Picks the format from the file extension and sniffs the first bytes otherwise.
+    pub fn detect(path: Option<&Path>, head: &[u8]) -> Format {
+        let extension = path
+            .and_then(|p| p.extension())
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("json") => Format::Json,
+            Some("ndjson") | Some("jsonl") => Format::Ndjson,
+            Some("csv") => Format::Csv,
+            _ => match head.iter().find(|b| !b.is_ascii_whitespace()) {
+                Some(b'[') => Format::Json,
+                Some(b'{') => Format::Ndjson,
+                _ => Format::Csv,
+            },
+        }
+    }
+}
+
+// A single record that could not be read, located as precisely as the format allows.
+#[derive(Debug, Clone)]
+pub struct RecordError {
+    pub line: Option<u64>,
+    pub record: Option<u64>,
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {line}: ")?;
+        } else if let Some(record) = self.record {
+            write!(f, "record {record}: ")?;
+        }
+
+        if let Some(field) = &self.field {
+            write!(f, "field `{field}`: ")?;
+        }
+
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+impl RecordError {
+    // A JSON document that failed to parse, located by line only.
+    pub fn from_json(error: &serde_json::Error) -> Self {
+        RecordError {
+            line: Some(error.line() as u64),
+            record: None,
+            field: None,
+            message: error.to_string(),
+        }
+    }
+}
+
+// Reads a JSON config file of `dataset` as a whole.
+pub fn read_config<T: DeserializeOwned>(
+    path: &Path,
+    dataset: &'static str,
+) -> Result<T, LoadError> {
+    let content = fs::read_to_string(path).map_err(|source| LoadError::Io {
+        dataset,
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    serde_json::from_str(&content).map_err(|e| LoadError::Record {
+        dataset,
+        error: RecordError::from_json(&e),
+    })
+}
+
+type Records<'a, T> = Box<dyn Iterator<Item = Result<T, RecordError>> + 'a>;
+
+// Streams the records of a dataset into `handle`, stopping at the first error it returns.
+// Every item is parsed on its own, so one bad row does not hide the position of the next one.
+pub fn read_records<T, R, E>(
+    reader: R,
+    format: Format,
+    mut handle: impl FnMut(Result<T, RecordError>) -> Result<(), E>,
+) -> Result<(), E>
+where
+    T: DeserializeOwned,
+    R: BufRead,
+{
+    match format {
+        Format::Json => json_records(reader, &mut handle),
+        Format::Ndjson => ndjson_records(reader).try_for_each(handle),
+        Format::Csv => csv_records(reader).try_for_each(handle),
+    }
+}
+
+// Counts the lines read so far. serde_json reads a byte at a time and has peeked at the
+// first byte of an element before deserializing it, so that is on the current line.
+struct LineCounter<R> {
+    inner: R,
+    newlines: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for LineCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let newlines = buf[..read].iter().filter(|b| **b == b'\n').count() as u64;
+        self.newlines.set(self.newlines.get() + newlines);
+        Ok(read)
+    }
+}
+
+// An element of the array as a JSON value, along with the line it starts on.
+struct Located<'a>(&'a Cell<u64>);
+
+impl<'de> DeserializeSeed<'de> for Located<'_> {
+    type Value = (u64, serde_json::Value);
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let line = self.0.get() + 1;
+        serde_json::Value::deserialize(deserializer).map(|value| (line, value))
+    }
+}
+
+// Hands the elements of the array to `handle` one by one. An error from `handle` is kept
+// in `stopped`, as the deserializer can only pass on its own errors.
+struct Elements<'a, T, F, E> {
+    newlines: &'a Cell<u64>,
+    handle: &'a mut F,
+    stopped: &'a mut Option<E>,
+    record: PhantomData<T>,
+}
+
+impl<'de, T, F, E> Visitor<'de> for Elements<'_, T, F, E>
+where
+    T: DeserializeOwned,
+    F: FnMut(Result<T, RecordError>) -> Result<(), E>,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an array of records")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut record = 0;
+        while let Some((line, value)) = seq.next_element_seed(Located(self.newlines))? {
+            record += 1;
+            let parsed = serde_path_to_error::deserialize(value).map_err(|e| RecordError {
+                line: Some(line),
+                record: Some(record),
+                field: field_from_path(e.path()),
+                message: e.into_inner().to_string(),
+            });
+
+            if let Err(error) = (self.handle)(parsed) {
+                *self.stopped = Some(error);
+                return Err(de::Error::custom("stopped"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Parses the array element by element, so only one record is held as a JSON value at a time.
+fn json_records<T, R, E>(
+    reader: R,
+    handle: &mut impl FnMut(Result<T, RecordError>) -> Result<(), E>,
+) -> Result<(), E>
+where
+    T: DeserializeOwned,
+    R: Read,
+{
+    let newlines = Rc::new(Cell::new(0));
+    let mut deserializer = serde_json::Deserializer::from_reader(LineCounter {
+        inner: reader,
+        newlines: Rc::clone(&newlines),
+    });
+
+    let mut stopped = None;
+    let result = deserializer
+        .deserialize_seq(Elements {
+            newlines: &newlines,
+            handle,
+            stopped: &mut stopped,
+            record: PhantomData,
+        })
+        .and_then(|_| deserializer.end());
+
+    if let Some(error) = stopped {
+        return Err(error);
+    }
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => handle(Err(RecordError::from_json(&e))),
+    }
+}
+
+fn ndjson_records<'a, T, R>(reader: R) -> Records<'a, T>
+where
+    T: DeserializeOwned + 'a,
+    R: BufRead + 'a,
+{
+    let records = reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(l) if l.trim().is_empty()))
+        .enumerate()
+        .map(|(record, (i, line))| {
+            let error = |field, message| RecordError {
+                line: Some(i as u64 + 1),
+                record: Some(record as u64 + 1),
+                field,
+                message,
+            };
+
+            let line = line.map_err(|e| error(None, e.to_string()))?;
+            let mut deserializer = serde_json::Deserializer::from_str(&line);
+
+            serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+                let field = field_from_path(e.path());
+                error(field, json_message(e.into_inner()))
+            })
+        });
+
+    Box::new(records)
+}
+
+fn csv_records<'a, T, R>(reader: R) -> Records<'a, T>
+where
+    T: DeserializeOwned + 'a,
+    R: Read + 'a,
+{
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => return Box::new(std::iter::once(Err(csv_error(e, None)))),
+    };
+
+    Box::new(
+        reader
+            .into_deserialize()
+            .map(move |result| result.map_err(|e| csv_error(e, Some(&headers)))),
+    )
+}
+
+fn csv_error(error: csv::Error, headers: Option<&csv::StringRecord>) -> RecordError {
+    let position = error.position().cloned();
+
+    let (field, message) = match error.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => (
+            err.field()
+                .and_then(|i| headers.and_then(|h| h.get(i as usize)))
+                .map(str::to_string),
+            err.kind().to_string(),
+        ),
+        _ => (None, error.to_string()),
+    };
+
+    RecordError {
+        line: position.as_ref().map(|p| p.line()),
+        record: position.as_ref().map(|p| p.record()),
+        field,
+        message,
+    }
+}
+
+// serde_json appends the position to its messages, which is always line 1 for a single row.
+fn json_message(error: serde_json::Error) -> String {
+    let position = format!(" at line {} column {}", error.line(), error.column());
+    let message = error.to_string();
+
+    message
+        .strip_suffix(&position)
+        .map(|m| format!("{m} (column {})", error.column()))
+        .unwrap_or(message)
+}
+
+fn field_from_path(path: &serde_path_to_error::Path) -> Option<String> {
+    let path = path.to_string();
+
+    if path == "." {
+        None
+    } else {
+        Some(path)
+    }
+}
@@ -1,66 +1,293 @@
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 
-use actix_web::patch;
+use actix_web::http::header;
 use actix_web::web::Data;
+use actix_web::{delete, patch, post, put};
 use actix_web::{
     get,
     web::{self},
     App, HttpRequest, HttpResponse, HttpServer, Responder, Result,
 };
 
-use data::PostcodeInfo;
+use autocomplete::PostcodeIndex;
+use clap::{Parser, Subcommand};
+use data::{PostalCode, PostcodeGroup, PostcodeInfo};
 use env_logger::Env;
-use map::Map;
+use error::{
+    check_areas, check_distance, check_score, check_travel_time, parse_id, parse_postcode, ApiError,
+};
+use geo::{Feature, Geometry};
+use geocode::Geocoder;
+use map::{Coverage, Map, Reach, Suggestion};
+use ranking::{Order, Rankers, RankingConfig, RankingProfiles, ScoreRanker, DEFAULT_PROFILE};
+use routing::RoadGraph;
+use search::{paginate, search_key, Cursor, Filter, Ranked, SortKey};
 use serde::{Deserialize, Serialize};
-use simsearch::SimSearch;
+use store::{MemoryStore, Store, WalStore};
+use validation::{validate_position, LoadError, ValidationReport, MAX_DRIVING_DISTANCE};
 
-use crate::data::ServiceProvider;
+use crate::data::{Address, NewServiceProvider, ServiceProvider, ServiceProviderView};
+mod autocomplete;
+mod bench;
 mod data;
+mod error;
+mod gaps;
+mod geo;
+mod geocode;
+mod import;
 mod map;
+mod ranking;
+mod routing;
+mod search;
+mod store;
+mod synthetic;
+mod validation;
+
+#[derive(Parser, Debug, Clone)]
+#[command(about = "Craftsmen search backend")]
+struct Args {
+    /// Postcode dataset (postcode.json)
+    #[arg(long, env = "POSTCODE_DATA")]
+    postcodes: Option<PathBuf>,
+
+    /// Service provider dataset (service_provider_profile.json)
+    #[arg(long, env = "SERVICE_PROVIDER_DATA")]
+    providers: Option<PathBuf>,
+
+    /// Quality factor dataset (quality_factor_score.json)
+    #[arg(long, env = "QUALITY_FACTOR_DATA")]
+    quality: Option<PathBuf>,
+
+    /// Skip invalid records instead of refusing to start
+    #[arg(long, env = "LENIENT_LOADING")]
+    lenient: bool,
+
+    /// Postcode extension groups (JSON object of group name to metres)
+    #[arg(long, env = "POSTCODE_GROUPS")]
+    groups: Option<PathBuf>,
+
+    /// Ranking weight profiles (JSON object of profile name to weights)
+    #[arg(long, env = "RANKING_CONFIG")]
+    ranking_config: Option<PathBuf>,
+
+    /// Road graph (JSON nodes and edges exported from OSM) for driving distances, straight lines if unset
+    #[arg(long, env = "ROAD_GRAPH")]
+    roads: Option<PathBuf>,
+
+    /// Write-ahead log persisting craftsman updates, kept in memory only if unset
+    #[arg(long, env = "STORE_PATH")]
+    store: Option<PathBuf>,
+
+    /// Zipcode gazetteer used for the autocomplete search
+    #[arg(long, env = "ZIPCODE_DATA", default_value = "data/zipcodes.de.json")]
+    zipcodes: PathBuf,
+
+    /// Address gazetteer for geocoding new providers, postcode and city centres only if unset
+    #[arg(long, env = "GAZETTEER_DATA")]
+    gazetteer: Option<PathBuf>,
+
+    /// Bearer token for the admin endpoints, which are disabled if unset
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Print the postcodes served by too few craftsmen as JSON instead of starting the server
+    CoverageGaps {
+        /// Report postcodes with fewer craftsmen than this
+        #[arg(long, default_value_t = 1)]
+        min_providers: usize,
+    },
+    /// Time index builds, searches and updates on synthetic data
+    Bench {
+        #[arg(long, default_value_t = 100000)]
+        providers: u32,
+        #[arg(long, default_value_t = 10000)]
+        postcodes: u32,
+        #[arg(long, default_value_t = 10000)]
+        updates: u32,
+    },
+}
 
 #[derive(Serialize, Deserialize)]
 struct SearchRequest {
     q: String,
+    limit: Option<usize>,
 }
 
 #[get("/zipcode/search")]
-async fn zipcode_search(req: HttpRequest, query: web::Query<SearchRequest>) -> impl Responder {
-    let postcode_engine: &SimSearch<PostcodeInfo> = req
-        .app_data()
-        .expect("Postcode engine not found in app data.");
+async fn zipcode_search(
+    postcode_index: Data<PostcodeIndex>,
+    query: web::Query<SearchRequest>,
+) -> Result<HttpResponse, ApiError> {
+    const DEFAULT_LIMIT: usize = 10;
+    const MAX_LIMIT: usize = 50;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if limit == 0 {
+        return Err(ApiError::BadRequest(
+            "`limit` must be at least 1.".to_string(),
+        ));
+    }
 
-    let mut res = postcode_engine.search(&query.q);
-    res.truncate(10);
+    let res = postcode_index.search(&query.q, limit.min(MAX_LIMIT));
 
-    HttpResponse::Ok()
+    Ok(HttpResponse::Ok()
         .content_type("application/json")
-        .body(serde_json::to_string(&res).unwrap())
+        .body(serde_json::to_string(&res).unwrap()))
+}
+
+#[derive(Deserialize)]
+struct RankingRequest {
+    profile: Option<String>,
+}
+
+// Providers out of reach offered when nobody serves a postcode.
+const SUGGESTIONS: usize = 5;
+
+#[derive(Serialize)]
+struct SearchResponse {
+    results: Vec<ServiceProviderView>,
+    // Closest providers out of reach, only filled when nobody serves the postcode
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    suggestions: Vec<Suggestion>,
 }
 
 #[get("/craftsmen/{postalcode}")]
 async fn craftsmen_search(
     path: web::Path<String>,
+    query: web::Query<RankingRequest>,
     data: Data<RwLock<Map>>,
-) -> Result<impl Responder> {
-    let postalcode = path.into_inner();
+) -> Result<impl Responder, ApiError> {
+    let postalcode = parse_postcode(&path)?;
     let map = data.read().unwrap();
+    let ranking = ranking_profile(&map, query.profile.as_deref())?;
+
+    let mut service_providers = map
+        .ranked(
+            postalcode,
+            &[SortKey::natural(&ScoreRanker)],
+            &Filter::default(),
+            ranking,
+            chrono::Utc::now().timestamp(),
+        )
+        .unwrap_or_default();
+    service_providers.truncate(20);
+
+    let suggestions = if service_providers.is_empty() {
+        map.suggestions(postalcode, SUGGESTIONS).unwrap_or_default()
+    } else {
+        vec![]
+    };
 
-    Ok(HttpResponse::Ok().content_type("application/json").body(
-        if let Some(mut service_providers) = map.ranked_by_score(postalcode.parse().unwrap()) {
-            service_providers.truncate(20);
-            serde_json::to_string(&service_providers).unwrap()
-        } else {
-            "[]".to_string()
-        },
-    ))
+    Ok(HttpResponse::Ok().json(SearchResponse {
+        results: service_providers.into_iter().map(Ranked::view).collect(),
+        suggestions,
+    }))
+}
+
+#[derive(Deserialize)]
+struct NearRequest {
+    lat: f64,
+    lon: f64,
+    // Extension group of the location, no extension unless this or `radius` is given
+    group: Option<String>,
+    // Kilometres added to the driving distance of every provider
+    radius: Option<f64>,
+    profile: Option<String>,
+}
+
+impl NearRequest {
+    fn reach(&self) -> Result<Reach, ApiError> {
+        match (&self.group, self.radius) {
+            (Some(_), Some(_)) => Err(ApiError::BadRequest(
+                "Use either `group` or `radius`, not both.".to_string(),
+            )),
+            (Some(group), None) => group
+                .parse()
+                .map(Reach::Group)
+                .map_err(ApiError::BadRequest),
+            (None, Some(km)) if (0.0..=MAX_DRIVING_DISTANCE as f64 / 1000.0).contains(&km) => {
+                Ok(Reach::Radius((km * 1000.0).round() as u64))
+            }
+            (None, Some(km)) => Err(ApiError::BadRequest(format!(
+                "`radius` must be between 0 and {} kilometres, got {km}.",
+                MAX_DRIVING_DISTANCE / 1000
+            ))),
+            (None, None) => Ok(Reach::Radius(0)),
+        }
+    }
+}
+
+// Must be registered before `/craftsmen/{postalcode}`, which would match it too.
+#[get("/craftsmen/near")]
+async fn craftsmen_near(
+    query: web::Query<NearRequest>,
+    data: Data<RwLock<Map>>,
+) -> Result<impl Responder, ApiError> {
+    let point = (query.lon.to_radians(), query.lat.to_radians());
+    validate_position(point.0, point.1).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let reach = query.reach()?;
+
+    let map = data.read().unwrap();
+    let ranking = ranking_profile(&map, query.profile.as_deref())?;
+
+    let mut service_providers = map.ranked_at(
+        point,
+        reach,
+        &[SortKey::natural(&ScoreRanker)],
+        &Filter::default(),
+        ranking,
+        chrono::Utc::now().timestamp(),
+    );
+    service_providers.truncate(20);
+    let views: Vec<ServiceProviderView> = service_providers.into_iter().map(Ranked::view).collect();
+
+    Ok(HttpResponse::Ok().json(views))
 }
 
 #[derive(Deserialize)]
+struct NearestRequest {
+    k: Option<usize>,
+}
+
+// The closest craftsmen whether or not they reach the postcode, for when nobody does.
+#[get("/craftsmen/{postalcode}/nearest")]
+async fn craftsmen_nearest(
+    path: web::Path<String>,
+    query: web::Query<NearestRequest>,
+    data: Data<RwLock<Map>>,
+) -> Result<impl Responder, ApiError> {
+    let postalcode = parse_postcode(&path)?;
+
+    const DEFAULT_NEAREST: usize = 5;
+    const MAX_NEAREST: usize = 50;
+    let k = query.k.unwrap_or(DEFAULT_NEAREST);
+    if k == 0 {
+        return Err(ApiError::BadRequest("`k` must be at least 1.".to_string()));
+    }
+
+    let map = data.read().unwrap();
+    let nearest = map
+        .nearest_to_postcode(postalcode, k.min(MAX_NEAREST))
+        .ok_or_else(|| ApiError::NotFound(format!("Unknown postal code {postalcode}.")))?;
+
+    Ok(HttpResponse::Ok().json(nearest))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct UpdateRequest {
-    maxDrivingDistance: Option<u64>,
-    profilePictureScore: Option<f64>,
-    profileDescriptionScore: Option<f64>,
+    max_driving_distance: Option<u64>,
+    // Minutes, 0 goes back to the driving distance
+    max_travel_time: Option<u64>,
+    profile_picture_score: Option<f64>,
+    profile_description_score: Option<f64>,
 }
 
 #[derive(Serialize)]
@@ -70,10 +297,13 @@ struct UpdateResponse {
 }
 
 #[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 struct UpdatedFields {
-    maxDrivingDistance: u64,
-    profilePictureScore: f64,
-    profileDescriptionScore: f64,
+    max_driving_distance: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_travel_time: Option<u64>,
+    profile_picture_score: f64,
+    profile_description_score: f64,
 }
 
 #[patch("/craftman/{craftman_id}")]
@@ -81,22 +311,30 @@ async fn craftsmen_update(
     info: web::Json<UpdateRequest>,
     path: web::Path<String>,
     data: Data<RwLock<Map>>,
-) -> Result<impl Responder> {
-    let craftmen_id = path.into_inner().parse().unwrap();
+) -> Result<impl Responder, ApiError> {
+    let craftmen_id = parse_id(&path)?;
+    check_distance("maxDrivingDistance", info.max_driving_distance)?;
+    check_travel_time("maxTravelTime", info.max_travel_time)?;
+    check_score("profilePictureScore", info.profile_picture_score)?;
+    check_score("profileDescriptionScore", info.profile_description_score)?;
+
     let mut map = data.write().unwrap();
 
-    let (maxDrivingDistance, profilePictureScore, profileDescriptionScore) = map
+    let (provider, quality) = map
         .update_service_provider(
             craftmen_id,
-            info.maxDrivingDistance,
-            info.profilePictureScore,
-            info.profileDescriptionScore,
-        );
+            info.max_driving_distance,
+            info.max_travel_time,
+            info.profile_picture_score,
+            info.profile_description_score,
+        )?
+        .ok_or_else(|| ApiError::craftman_not_found(craftmen_id))?;
 
     let updated_fields = UpdatedFields {
-        maxDrivingDistance,
-        profilePictureScore,
-        profileDescriptionScore,
+        max_driving_distance: provider.max_driving_distance,
+        max_travel_time: provider.max_travel_time,
+        profile_picture_score: quality.profile_picture_score,
+        profile_description_score: quality.profile_description_score,
     };
 
     let response = UpdateResponse {
@@ -108,18 +346,201 @@ async fn craftsmen_update(
     Ok(HttpResponse::Ok().json(response))
 }
 
+#[post("/craftmen")]
+async fn craftsmen_create(
+    info: web::Json<NewServiceProvider>,
+    data: Data<RwLock<Map>>,
+    geocoder: Data<Geocoder>,
+) -> Result<impl Responder, ApiError> {
+    let mut provider = info.into_inner();
+    check_distance("maxDrivingDistance", Some(provider.max_driving_distance))?;
+    check_travel_time("maxTravelTime", provider.max_travel_time)?;
+    check_score("profilePictureScore", Some(provider.profile_picture_score))?;
+    check_score(
+        "profileDescriptionScore",
+        Some(provider.profile_description_score),
+    )?;
+    check_areas(provider.service_area.as_ref(), &provider.excluded_areas)?;
+    locate(&geocoder, &mut provider.address)?;
+
+    let mut map = data.write().unwrap();
+    let created = map.create_service_provider(provider)?;
+
+    Ok(HttpResponse::Created().json(created))
+}
+
+#[put("/craftman/{craftman_id}")]
+async fn craftsmen_relocate(
+    info: web::Json<Address>,
+    path: web::Path<String>,
+    data: Data<RwLock<Map>>,
+    geocoder: Data<Geocoder>,
+) -> Result<impl Responder, ApiError> {
+    let craftmen_id = parse_id(&path)?;
+    let mut address = info.into_inner();
+    locate(&geocoder, &mut address)?;
+
+    let mut map = data.write().unwrap();
+    let updated = map
+        .relocate_service_provider(craftmen_id, address)?
+        .ok_or_else(|| ApiError::craftman_not_found(craftmen_id))?;
+
+    Ok(HttpResponse::Ok().json(updated))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AreasRequest {
+    // GeoJSON, the driving distance or travel time applies again if left out
+    service_area: Option<Geometry>,
+    #[serde(default)]
+    excluded_areas: Vec<Geometry>,
+}
+
+#[put("/craftman/{craftman_id}/areas")]
+async fn craftsmen_areas(
+    info: web::Json<AreasRequest>,
+    path: web::Path<String>,
+    data: Data<RwLock<Map>>,
+) -> Result<impl Responder, ApiError> {
+    let craftmen_id = parse_id(&path)?;
+    let AreasRequest {
+        service_area,
+        excluded_areas,
+    } = info.into_inner();
+    check_areas(service_area.as_ref(), &excluded_areas)?;
+
+    let mut map = data.write().unwrap();
+    let updated = map
+        .set_service_areas(craftmen_id, service_area, excluded_areas)?
+        .ok_or_else(|| ApiError::craftman_not_found(craftmen_id))?;
+
+    Ok(HttpResponse::Ok().json(updated))
+}
+
+#[delete("/craftman/{craftman_id}")]
+async fn craftsmen_delete(
+    path: web::Path<String>,
+    data: Data<RwLock<Map>>,
+) -> Result<impl Responder, ApiError> {
+    let craftmen_id = parse_id(&path)?;
+    let mut map = data.write().unwrap();
+
+    if map.delete_service_provider(craftmen_id)? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ApiError::craftman_not_found(craftmen_id))
+    }
+}
+
+#[derive(Deserialize)]
+struct CoverageRequest {
+    // `json` (default) or `geojson`
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CoverageResponse {
+    id: u32,
+    max_driving_distance: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_travel_time: Option<u64>,
+    count: usize,
+    postcodes: Vec<Coverage>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CoverageProperties {
+    id: u32,
+    max_driving_distance: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_travel_time: Option<u64>,
+    postcodes: Vec<PostalCode>,
+}
+
+#[get("/craftman/{craftman_id}/coverage")]
+async fn craftsmen_coverage(
+    path: web::Path<String>,
+    query: web::Query<CoverageRequest>,
+    data: Data<RwLock<Map>>,
+) -> Result<impl Responder, ApiError> {
+    let craftmen_id = parse_id(&path)?;
+    let map = data.read().unwrap();
+
+    let provider = map
+        .service_provider_by_id(craftmen_id)
+        .ok_or_else(|| ApiError::craftman_not_found(craftmen_id))?;
+    let postcodes = map
+        .coverage(craftmen_id)
+        .ok_or_else(|| ApiError::craftman_not_found(craftmen_id))?;
+
+    match query.format.as_deref().unwrap_or("json") {
+        "json" => Ok(HttpResponse::Ok().json(CoverageResponse {
+            id: craftmen_id,
+            max_driving_distance: provider.max_driving_distance,
+            max_travel_time: provider.max_travel_time,
+            count: postcodes.len(),
+            postcodes,
+        })),
+        "geojson" => {
+            let points: Vec<[f64; 2]> = postcodes.iter().map(|c| [c.lon, c.lat]).collect();
+            let feature = Feature::new(
+                geo::hull_geometry(&points),
+                CoverageProperties {
+                    id: craftmen_id,
+                    max_driving_distance: provider.max_driving_distance,
+                    max_travel_time: provider.max_travel_time,
+                    postcodes: postcodes.iter().map(|c| c.postcode).collect(),
+                },
+            );
+
+            Ok(HttpResponse::Ok()
+                .content_type("application/geo+json")
+                .body(serde_json::to_string(&feature).unwrap()))
+        }
+        format => Err(ApiError::BadRequest(format!(
+            "Unknown format `{format}`, use `json` or `geojson`."
+        ))),
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct DetailedRequest {
-    page: Option<u32>,
+    cursor: Option<String>,
+    limit: Option<usize>,
     sort: Option<String>,
+    order: Option<String>,
+    profile: Option<String>,
+    min_quality: Option<f64>,
+    // Kilometres
+    max_distance: Option<f64>,
+    city: Option<String>,
+    name: Option<String>,
+}
+
+impl DetailedRequest {
+    fn filter(&self) -> Filter {
+        Filter {
+            min_quality: self.min_quality,
+            max_distance: self.max_distance.map(|km| km * 1000.0),
+            city: self.city.clone(),
+            name: self.name.clone(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct DetailedResponse {
     has_more: bool,
+    next_cursor: Option<String>,
     total_count: usize,
     results: Vec<ServiceProvider>,
     postcode_info: Option<PostcodeInfo>,
+    // Closest providers out of reach, only filled when nobody serves the postcode
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    suggestions: Vec<Suggestion>,
 }
 
 #[get("/craftsmen/{postalcode}/detailed")]
@@ -127,97 +548,378 @@ async fn craftsmen_search_detailed(
     path: web::Path<String>,
     query: web::Query<DetailedRequest>,
     data: Data<RwLock<Map>>,
-    postcode_info_map: Data<RwLock<HashMap<u32, PostcodeInfo>>>,
-) -> Result<impl Responder> {
-    let postalcode: u32 = path.into_inner().parse().unwrap();
+    rankers: Data<Rankers>,
+    postcode_info_map: Data<RwLock<HashMap<PostalCode, PostcodeInfo>>>,
+) -> Result<impl Responder, ApiError> {
+    let postalcode = parse_postcode(&path)?;
     let map = data.read().unwrap();
 
     let postcode_info = postcode_info_map.read().unwrap();
     let postcode_details = postcode_info.get(&postalcode);
-
-    let Some(mut service_providers) = (match query.sort.as_deref() {
-        Some("distance") => map.ranked_by_distance(postalcode),
-        Some("profile") => map.ranked_by_profile(postalcode),
-        _ => map.ranked_by_score(postalcode),
-    }) else {
-        return Ok(HttpResponse::Ok()
-            .content_type("application/json")
-            .body(
-                serde_json::to_string(&DetailedResponse {
-                    has_more: false,
-                    total_count: 0,
-                    results: vec![],
-                    postcode_info: postcode_details.map(|x| x.clone()),
-                })
-                .unwrap(),
-            ));
+    let ranking = ranking_profile(&map, query.profile.as_deref())?;
+
+    let sort = query.sort.as_deref().unwrap_or("score");
+    let keys =
+        SortKey::parse(sort, query.order.as_deref(), &rankers).map_err(ApiError::BadRequest)?;
+    let filter = query.filter();
+    let search = search_key(
+        &keys,
+        query.profile.as_deref().unwrap_or(DEFAULT_PROFILE),
+        postalcode,
+        &filter,
+    );
+
+    let cursor = match &query.cursor {
+        Some(token) => Some(Cursor::decode(token).map_err(ApiError::BadRequest)?),
+        None => None,
+    };
+    // Freshness decays while paging, so later pages are scored as of the first one.
+    let now = cursor
+        .as_ref()
+        .map_or_else(|| chrono::Utc::now().timestamp(), Cursor::at);
+
+    const DEFAULT_PAGE_SIZE: usize = 20;
+    const MAX_PAGE_SIZE: usize = 100;
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit == 0 {
+        return Err(ApiError::BadRequest(
+            "`limit` must be at least 1.".to_string(),
+        ));
+    }
+    let limit = limit.min(MAX_PAGE_SIZE);
+
+    let Some(service_providers) = map.ranked(postalcode, &keys, &filter, ranking, now) else {
+        return Ok(HttpResponse::Ok().content_type("application/json").body(
+            serde_json::to_string(&DetailedResponse {
+                has_more: false,
+                next_cursor: None,
+                total_count: 0,
+                results: vec![],
+                postcode_info: postcode_details.cloned(),
+                suggestions: vec![],
+            })
+            .unwrap(),
+        ));
     };
 
     let total_count = service_providers.len();
+    let suggestions = if total_count == 0 {
+        map.suggestions(postalcode, SUGGESTIONS).unwrap_or_default()
+    } else {
+        vec![]
+    };
 
-    const PAGE_SIZE: u32 = 20;
-    let start = query.page.unwrap_or(0) * PAGE_SIZE;
-
-    service_providers = service_providers.split_off(start as usize);
-    let has_more = service_providers.len() > PAGE_SIZE as usize;
-    service_providers.truncate(PAGE_SIZE as usize);
-
-    let detailed: Vec<ServiceProvider> = service_providers
+    let page = paginate(
+        service_providers,
+        &keys,
+        search,
+        now,
+        cursor.as_ref(),
+        limit,
+    )
+    .map_err(ApiError::BadRequest)?;
+
+    let detailed: Vec<ServiceProvider> = page
+        .items
         .iter()
-        .map(|sp| map.service_provider_by_id(sp.id))
-        .filter(|sp| sp.is_some())
-        .map(|sp| sp.unwrap())
+        .filter_map(|sp| map.service_provider_by_id(sp.id))
         .collect();
 
     Ok(HttpResponse::Ok().content_type("application/json").body(
         serde_json::to_string(&DetailedResponse {
-            has_more,
-            total_count: total_count,
+            has_more: page.next_cursor.is_some(),
+            next_cursor: page.next_cursor,
+            total_count,
             results: detailed,
-            postcode_info: postcode_details.map(|x| x.clone()),
+            postcode_info: postcode_details.cloned(),
+            suggestions,
         })
         .unwrap(),
     ))
 }
 
-pub fn build_engine(postcodes: &Vec<PostcodeInfo>) -> SimSearch<PostcodeInfo> {
-    let mut engine: SimSearch<PostcodeInfo> = SimSearch::new();
+#[derive(Serialize)]
+struct RankerInfo {
+    name: &'static str,
+    description: &'static str,
+    order: Order,
+}
+
+#[derive(Serialize)]
+struct RankingsResponse<'a> {
+    rankers: Vec<RankerInfo>,
+    profiles: Vec<&'a str>,
+}
+
+#[get("/rankings")]
+async fn rankings(rankers: Data<Rankers>, data: Data<RwLock<Map>>) -> impl Responder {
+    let map = data.read().unwrap();
+
+    HttpResponse::Ok().json(RankingsResponse {
+        rankers: rankers
+            .iter()
+            .map(|r| RankerInfo {
+                name: r.name(),
+                description: r.description(),
+                order: r.order(),
+            })
+            .collect(),
+        profiles: map.ranking_profile_names(),
+    })
+}
+
+#[derive(Deserialize)]
+struct GapsRequest {
+    // Postcodes with fewer craftsmen than this are reported, 1 by default
+    min_providers: Option<usize>,
+}
+
+#[get("/analytics/coverage-gaps")]
+async fn coverage_gaps(
+    query: web::Query<GapsRequest>,
+    data: Data<RwLock<Map>>,
+    postcode_info_map: Data<RwLock<HashMap<PostalCode, PostcodeInfo>>>,
+) -> Result<impl Responder, ApiError> {
+    let min_providers = query.min_providers.unwrap_or(1);
+    if min_providers == 0 {
+        return Err(ApiError::BadRequest(
+            "`min_providers` must be at least 1.".to_string(),
+        ));
+    }
+
+    let map = data.read().unwrap();
+    let postcode_info = postcode_info_map.read().unwrap();
+
+    Ok(HttpResponse::Ok().json(gaps::coverage_gaps(&map, &postcode_info, min_providers)))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReloadResponse {
+    postcodes: usize,
+    service_providers: usize,
+    dropped: usize,
+}
+
+#[post("/admin/reload")]
+async fn reload_data(
+    req: HttpRequest,
+    args: Data<Args>,
+    data: Data<RwLock<Map>>,
+) -> Result<impl Responder, ApiError> {
+    check_admin(&req, args.admin_token.as_deref())?;
+
+    // Parse and build off the request path while searches keep using the old map.
+    let (mut map, report) = web::block(move || load_map(&args))
+        .await?
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+    let response = ReloadResponse {
+        postcodes: map.postcode_count(),
+        service_providers: map.service_provider_count(),
+        dropped: report.dropped().len(),
+    };
+
+    // Readers hold the lock for a whole request, so they never see a half-swapped map.
+    // Replaying under the write lock makes sure no update slips in between.
+    let previous = {
+        let mut current = data.write().unwrap();
+        map.attach_store(current.store())?;
+        std::mem::replace(&mut *current, map)
+    };
+    drop(previous);
+
+    println!(
+        "Reloaded {} postcodes and {} service providers.",
+        response.postcodes, response.service_providers
+    );
+    println!("{report}");
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+// Geocodes addresses given without coordinates and checks the result.
+fn locate(geocoder: &Geocoder, address: &mut Address) -> Result<(), ApiError> {
+    if !geocoder.locate(address) {
+        return Err(ApiError::Unprocessable(format!(
+            "Could not find `{} {}, {}`, please pass `lat` and `lon`.",
+            address.street, address.house_number, address.city
+        )));
+    }
+
+    address
+        .validate()
+        .map_err(|reason| ApiError::Unprocessable(reason.to_string()))
+}
+
+// Admin endpoints need `Authorization: Bearer <token>` with the configured token.
+fn check_admin(req: &HttpRequest, token: Option<&str>) -> Result<(), ApiError> {
+    let Some(token) = token.filter(|token| !token.is_empty()) else {
+        return Err(ApiError::Forbidden(
+            "Admin endpoints are disabled, set ADMIN_TOKEN to enable them.".to_string(),
+        ));
+    };
+
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    // Compare every byte, so the time taken doesn't tell how much of the token matched.
+    let matches = given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if !matches {
+        return Err(ApiError::Unauthorized(
+            "A valid admin token is required.".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn ranking_profile<'a>(map: &'a Map, name: Option<&str>) -> Result<&'a RankingConfig, ApiError> {
+    map.ranking_profile(name).ok_or_else(|| {
+        ApiError::BadRequest(format!(
+            "Unknown ranking profile `{}`.",
+            name.unwrap_or_default()
+        ))
+    })
+}
+
+fn load_map(args: &Args) -> Result<(Map, ValidationReport), LoadError> {
+    let mut report = ValidationReport::new(args.lenient);
+
+    // Postcodes name their group, so the groups come first.
+    if let Some(path) = &args.groups {
+        PostcodeGroup::configure_from_file(path)?;
+    }
+    let postcodes = data::postcode_from_file(args.postcodes.as_deref(), &mut report)?;
+    let mut service_providers = data::provider_from_file(args.providers.as_deref(), &mut report)?;
+    let quality_factor = data::quality_from_file(args.quality.as_deref(), &mut report)?;
+    data::drop_unrated_providers(&mut service_providers, &quality_factor, &mut report)?;
+
+    let ranking = match &args.ranking_config {
+        Some(path) => RankingProfiles::from_file(path)?,
+        None => RankingProfiles::default(),
+    };
+
+    let mut map = Map::new(postcodes, quality_factor, service_providers);
+    map.set_ranking_profiles(ranking);
+    if let Some(path) = &args.roads {
+        map.set_road_graph(Arc::new(RoadGraph::from_file(path)?));
+    }
+    Ok((map, report))
+}
+
+fn open_store(args: &Args) -> std::io::Result<Arc<dyn Store>> {
+    Ok(match &args.store {
+        Some(path) => Arc::new(WalStore::open(path)?),
+        None => Arc::new(MemoryStore),
+    })
+}
 
-    for info in postcodes {
-        let search_str = info.zipcode.to_string() + " " + &info.place;
-        engine.insert(info.clone(), &search_str);
+// Runs a one-off command instead of the server. Only the result goes to stdout.
+fn run_command(command: &Command, args: &Args) -> std::io::Result<()> {
+    match *command {
+        Command::CoverageGaps { min_providers } => {
+            let (map, postcode_to_info) = load_current(args)?;
+            let report = gaps::coverage_gaps(&map, &postcode_to_info, min_providers);
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        }
+        Command::Bench {
+            providers,
+            postcodes,
+            updates,
+        } => bench::run(providers, postcodes, updates),
     }
 
-    engine
+    Ok(())
+}
+
+// The map as the server would see it, including stored updates.
+fn load_current(args: &Args) -> std::io::Result<(Map, HashMap<PostalCode, PostcodeInfo>)> {
+    let postcode_info = data::postcode_info_from_file(&args.zipcodes)
+        .expect("Could not read postcode data from file.");
+    let postcode_to_info: HashMap<PostalCode, PostcodeInfo> = postcode_info
+        .into_iter()
+        .map(|pci| (pci.zipcode, pci))
+        .collect();
+
+    let (mut map, report) = match load_map(args) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    eprintln!("{report}");
+    map.attach_store(open_store(args)?)?;
+
+    Ok((map, postcode_to_info))
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    dotenv::dotenv().ok();
     env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
 
+    let args = Args::parse();
+
+    if let Some(command) = &args.command {
+        return run_command(command, &args);
+    }
+
     println!("Initializing web server...");
 
-    let postcode_info = data::postcode_info_from_file("data/zipcodes.de.json")
+    let postcode_info = data::postcode_info_from_file(&args.zipcodes)
         .expect("Could not read postcode data from file.");
 
-    let postcode_to_info: HashMap<u32, PostcodeInfo> = postcode_info
+    let postcode_to_info: HashMap<PostalCode, PostcodeInfo> = postcode_info
         .iter()
         .map(|pci| (pci.zipcode, pci.clone()))
         .collect();
 
-    let postcodes = data::postcode_from_file().unwrap();
-    let service_providers = data::provider_from_file().unwrap();
-    let quality_factor = data::quality_from_file().unwrap();
+    let (mut map, report) = match load_map(&args) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    println!("{report}");
+
+    map.attach_store(open_store(&args)?)?;
+
+    let mut gazetteer_report = ValidationReport::new(args.lenient);
+    let gazetteer = match &args.gazetteer {
+        Some(path) => match data::gazetteer_from_file(path, &mut gazetteer_report) {
+            Ok(entries) => {
+                println!("{gazetteer_report}");
+                entries
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
+    let geocoder = Data::new(Geocoder::new(&gazetteer, &postcode_info));
 
-    let map = Data::new(RwLock::new(Map::new(
-        postcodes,
-        quality_factor,
-        service_providers,
-    )));
+    let map = Data::new(RwLock::new(map));
 
     let postcode_to_info = Data::new(RwLock::new(postcode_to_info));
 
-    let postcode_engine = build_engine(&postcode_info);
+    let postcode_index = Data::new(PostcodeIndex::new(&postcode_info));
+
+    let args = Data::new(args);
+    let rankers = Data::new(Rankers::default());
 
     println!("Setup done.");
 
@@ -226,13 +928,31 @@ async fn main() -> std::io::Result<()> {
 
     HttpServer::new(move || {
         App::new()
-            .app_data(postcode_engine.clone())
+            .app_data(web::JsonConfig::default().error_handler(error::json_error))
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|e, _| ApiError::BadRequest(e.to_string()).into()),
+            )
+            .app_data(Data::clone(&postcode_index))
             .app_data(Data::clone(&map))
             .app_data(Data::clone(&postcode_to_info))
+            .app_data(Data::clone(&args))
+            .app_data(Data::clone(&rankers))
+            .app_data(Data::clone(&geocoder))
             .service(zipcode_search)
+            .service(craftsmen_near)
             .service(craftsmen_search)
             .service(craftsmen_search_detailed)
+            .service(craftsmen_nearest)
             .service(craftsmen_update)
+            .service(craftsmen_create)
+            .service(craftsmen_relocate)
+            .service(craftsmen_areas)
+            .service(craftsmen_delete)
+            .service(craftsmen_coverage)
+            .service(rankings)
+            .service(coverage_gaps)
+            .service(reload_data)
     })
     .bind(("0.0.0.0", 3000))?
     .run()
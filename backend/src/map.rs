@@ -1,332 +1,1363 @@
 use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::io;
+use std::slice;
+use std::sync::Arc;
 
-use rstar::{Envelope, Point, PointDistance, RTree, RTreeObject, SelectionFunction, AABB};
-use serde::Serialize;
+use rstar::{Envelope, PointDistance, RTree, RTreeObject, SelectionFunction, AABB};
+use serde::{Deserialize, Serialize};
 
-use crate::data::{Postcode, PostcodeGroup, QualityFactor, ServiceProvider, ServiceProviderView};
+use crate::data::{
+    Address, NewServiceProvider, PostalCode, Postcode, PostcodeGroup, QualityFactor,
+    ServiceProvider,
+};
+use crate::geo::{self, Geometry, Polygon};
+use crate::ranking::{Candidate, RankingConfig, RankingProfiles};
+use crate::routing::{estimate_distance, estimate_duration, Cost, RoadGraph, Route, Routed};
+use crate::search::{compare_scores, Filter, Ranked, SortKey};
+use crate::store::{Entry, MemoryStore, Mutation, Store};
 
+pub const EARTH_RADIUS: f64 = 6371000.0;
+
+// Providers are indexed as points on the unit sphere, so envelopes and distances in the
+// tree share one Euclidean space and nothing has to wrap at the poles or at ±180°.
 #[derive(Clone, Serialize)]
 pub struct InServiceProvider {
     id: u32,
     name: String,
     pos: (f64, f64),
-    min: (f64, f64),
-    max: (f64, f64),
+    // `pos` on the unit sphere
+    point: [f64; 3],
+    min: [f64; 3],
+    max: [f64; 3],
     max_driving_distance: u64,
+    // Minutes, `area` is the isochrone if there is a road graph
+    max_travel_time: Option<u64>,
+    // Set unless the provider simply serves a circle
+    #[serde(skip)]
+    area: Option<Arc<ServiceArea>>,
     rank: Option<f64>,
 }
 
-impl Into<(f64, f64)> for Postcode {
-    fn into(self) -> (f64, f64) {
-        (self.lon, self.lat)
+// A service area other than a plain circle around the provider: polygons served instead
+// of the driving distance, drawn as GeoJSON or the isochrone of a travel time, and zones
+// the provider refuses to enter.
+pub struct ServiceArea {
+    // [lon, lat] in degrees, empty to serve the circle of `radius`
+    polygons: Vec<Polygon>,
+    excluded: Vec<Polygon>,
+    // The polygons were drawn by the provider, so they aren't re-checked by road
+    drawn: bool,
+    // Metres served around the provider if there are no polygons
+    radius: u64,
+}
+
+impl ServiceArea {
+    // None for providers serving nothing but the circle of their driving distance or
+    // travel time.
+    pub fn of(provider: &ServiceProvider, roads: Option<&RoadGraph>) -> Option<Self> {
+        let pos = (provider.lon, provider.lat);
+        let polygons = match (&provider.service_area, provider.max_travel_time, roads) {
+            (Some(area), _, _) => area.polygons(),
+            (None, Some(minutes), Some(roads)) => {
+                isochrone(roads, pos, minutes).into_iter().collect()
+            }
+            _ => Vec::new(),
+        };
+        let excluded: Vec<Polygon> = provider
+            .excluded_areas
+            .iter()
+            .flat_map(Geometry::polygons)
+            .collect();
+
+        if polygons.is_empty() && excluded.is_empty() {
+            return None;
+        }
+
+        Some(ServiceArea {
+            polygons,
+            excluded,
+            drawn: provider.service_area.is_some(),
+            radius: reach_distance(provider),
+        })
+    }
+
+    // Polygons are indexed by their bounding box, circles by the cap around them, both
+    // grown by `extension`.
+    fn envelope(&self, pos: (f64, f64), extension: u64) -> AABB<[f64; 3]> {
+        match geo::bounds(&self.polygons) {
+            Some(bounds) => bounds_envelope(bounds, extension),
+            None => reach_envelope(pos, self.radius, extension),
+        }
+    }
+
+    // Whether `point` lies in the area served from `pos` or within `extension` metres of
+    // it. Excluded zones are never entered, whatever the extension.
+    fn reaches(&self, pos: (f64, f64), point: (f64, f64), extension: u64) -> bool {
+        self.shortfall(pos, point, extension)
+            .is_some_and(|shortfall| shortfall <= 0.0)
     }
+
+    // Metres `point` lies beyond the area served from `pos` and `extension`, not positive
+    // if it is reached. None in excluded zones, which no extra distance gets into.
+    fn shortfall(&self, pos: (f64, f64), point: (f64, f64), extension: u64) -> Option<f64> {
+        let degrees = [point.0.to_degrees(), point.1.to_degrees()];
+
+        if self
+            .excluded
+            .iter()
+            .any(|polygon| geo::polygon_contains(polygon, degrees))
+        {
+            return None;
+        }
+
+        let outside = if self.polygons.is_empty() {
+            Map::calculate_distance(pos, point) - self.radius as f64
+        } else if self
+            .polygons
+            .iter()
+            .any(|polygon| geo::polygon_contains(polygon, degrees))
+        {
+            0.0
+        } else {
+            self.polygons
+                .iter()
+                .map(|polygon| geo::distance_to_polygon(polygon, degrees))
+                .fold(f64::INFINITY, f64::min)
+        };
+
+        Some(outside - extension as f64)
+    }
+}
+
+// What the reach of a provider plus `extension` is measured in on the road graph and how
+// far it goes. Providers with a travel time take the fastest route, the others the
+// shortest. None for drawn areas, which are served as drawn.
+fn road_budget(x: &InServiceProvider, extension: u64) -> Option<(Cost, f64)> {
+    if x.area.as_ref().is_some_and(|area| area.drawn) {
+        return None;
+    }
+
+    Some(match x.max_travel_time {
+        Some(minutes) => (
+            Cost::Duration,
+            minutes.saturating_mul(60) as f64 + estimate_duration(extension as f64),
+        ),
+        None => (
+            Cost::Distance,
+            x.max_driving_distance.saturating_add(extension) as f64,
+        ),
+    })
 }
 
-impl Into<InServiceProvider> for ServiceProvider {
-    fn into(self) -> InServiceProvider {
-        let angular_radius = self.max_driving_distance as f64 / 6371000.0;
-        let delta_lon = (angular_radius.sin() / self.lat.cos()).asin();
+// The area within `minutes` of driving from `pos`, None outside the graph or if hardly
+// anything is reachable.
+fn isochrone(roads: &RoadGraph, pos: (f64, f64), minutes: u64) -> Option<Polygon> {
+    let reachable = roads.reachable(pos, minutes.saturating_mul(60) as f64)?;
+    let mut ring = geo::convex_hull(&reachable);
+    if ring.len() < 3 {
+        return None;
+    }
+
+    ring.push(ring[0]);
+    Some(vec![ring])
+}
+
+// Metres a provider serves around its position. Without an isochrone, a travel time is
+// driven at the default speed in every direction.
+fn reach_distance(provider: &ServiceProvider) -> u64 {
+    match provider.max_travel_time {
+        Some(minutes) => estimate_distance(minutes.saturating_mul(60) as f64).round() as u64,
+        None => provider.max_driving_distance,
+    }
+}
+
+impl From<Postcode> for (f64, f64) {
+    fn from(postcode: Postcode) -> Self {
+        (postcode.lon, postcode.lat)
+    }
+}
+
+// The unit vector of a (lon, lat) position in radians.
+pub fn unit_vector(pos: (f64, f64)) -> [f64; 3] {
+    let (lon, lat) = pos;
+    [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+}
+
+// Squared straight-line distance between two points on the unit sphere, the metric of
+// the tree. Grows monotonically with the great-circle distance.
+fn chord_2(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+// Great-circle distance in metres for a squared chord.
+fn chord_2_to_distance(chord_2: f64) -> f64 {
+    2.0 * (chord_2.sqrt() / 2.0).clamp(0.0, 1.0).asin() * EARTH_RADIUS
+}
+
+// Bounding box of everything a provider at `pos` reaches within `distance` metres plus
+// `extension`, the largest group extension, so a single tree answers the queries of every
+// group. Along each axis, the cap spans the angles to that axis within its angular radius.
+fn reach_envelope(pos: (f64, f64), distance: u64, extension: u64) -> AABB<[f64; 3]> {
+    let reach = distance.saturating_add(extension);
+    let angular_radius = (reach as f64 / EARTH_RADIUS).min(PI);
+    let point = unit_vector(pos);
+
+    let mut min = [0.0; 3];
+    let mut max = [0.0; 3];
+    for axis in 0..3 {
+        let angle = point[axis].clamp(-1.0, 1.0).acos();
+        // Pad by a few millimetres so rounding never drops points on the border.
+        max[axis] = (angle - angular_radius).max(0.0).cos() + 1e-9;
+        min[axis] = (angle + angular_radius).min(PI).cos() - 1e-9;
+    }
+
+    AABB::from_corners(min, max)
+}
+
+// Bounding box of a [[min lon, min lat], [max lon, max lat]] rectangle in degrees, grown
+// by `extension` metres. Each coordinate on the sphere is a product of one
+// factor depending on the longitude and one on the latitude, so its extremes lie on the
+// corners or where the rectangle crosses the equator or a multiple of 90° longitude.
+fn bounds_envelope(bounds: [[f64; 2]; 2], extension: u64) -> AABB<[f64; 3]> {
+    let [[lon_min, lat_min], [lon_max, lat_max]] = bounds.map(|p| p.map(f64::to_radians));
+
+    let mut lons = vec![lon_min, lon_max];
+    lons.extend(
+        (-2..=2)
+            .map(|k| k as f64 * PI / 2.0)
+            .filter(|lon| (lon_min..=lon_max).contains(lon)),
+    );
+    let mut lats = vec![lat_min, lat_max];
+    if (lat_min..=lat_max).contains(&0.0) {
+        lats.push(0.0);
+    }
+
+    let angle = (extension as f64 / EARTH_RADIUS).min(PI);
+    let padding = 2.0 * (angle / 2.0).sin() + 1e-9;
+
+    let mut min = [f64::INFINITY; 3];
+    let mut max = [f64::NEG_INFINITY; 3];
+    for &lon in &lons {
+        for &lat in &lats {
+            let point = unit_vector((lon, lat));
+            for axis in 0..3 {
+                min[axis] = min[axis].min(point[axis] - padding);
+                max[axis] = max[axis].max(point[axis] + padding);
+            }
+        }
+    }
+
+    AABB::from_corners(min, max)
+}
+
+impl InServiceProvider {
+    // Bounds the entry by the cap of its driving distance plus `extension`.
+    fn new(provider: &ServiceProvider, extension: u64) -> Self {
+        let pos = (provider.lon, provider.lat);
+        let distance = reach_distance(provider);
+        let envelope = reach_envelope(pos, distance, extension);
 
         InServiceProvider {
-            id: self.id,
-            name: self.first_name + self.last_name.as_str(),
-            pos: (self.lon, self.lat),
-            min: (self.lon - delta_lon, self.lat - angular_radius),
-            max: (self.lon + delta_lon, self.lat + angular_radius),
-            max_driving_distance: self.max_driving_distance,
+            id: provider.id,
+            name: format!("{}{}", provider.first_name, provider.last_name),
+            pos,
+            point: unit_vector(pos),
+            min: envelope.lower(),
+            max: envelope.upper(),
+            max_driving_distance: distance,
+            max_travel_time: provider.max_travel_time,
+            area: None,
             rank: None,
         }
     }
+
+    // Bounds the entry by the box around the area, which is then checked exactly. A drawn
+    // area need not contain the provider, whose position the box is grown to, or nearest
+    // neighbour queries would prune it.
+    fn with_area(mut self, area: Arc<ServiceArea>, extension: u64) -> Self {
+        let mut envelope = area.envelope(self.pos, extension);
+        envelope.merge(&AABB::from_point(self.point));
+        self.min = envelope.lower();
+        self.max = envelope.upper();
+        self.area = Some(area);
+        self
+    }
 }
 
 impl RTreeObject for InServiceProvider {
-    type Envelope = rstar::AABB<[f64; 2]>;
+    type Envelope = AABB<[f64; 3]>;
 
     fn envelope(&self) -> Self::Envelope {
-        AABB::from_corners([self.min.0, self.min.1], [self.max.0, self.max.1])
+        AABB::from_corners(self.min, self.max)
     }
 }
 
+// Distances are squared chords to the provider's position, which never fall below the
+// distance to its envelope. That keeps nearest neighbour queries exact.
 impl PointDistance for InServiceProvider {
-    fn contains_point(&self, point: &<Self::Envelope as Envelope>::Point) -> bool {
-        self.distance_2(point) <= self.max_driving_distance as f64
+    fn distance_2(&self, point: &[f64; 3]) -> f64 {
+        chord_2(&self.point, point)
     }
 
-    fn distance_2(
-        &self,
-        point: &<Self::Envelope as Envelope>::Point,
-    ) -> <<Self::Envelope as Envelope>::Point as Point>::Scalar {
-        let other_lon = point.get(0).unwrap();
-        let other_lat = point.get(1).unwrap();
+    fn contains_point(&self, point: &[f64; 3]) -> bool {
+        chord_2_to_distance(self.distance_2(point)) <= self.max_driving_distance as f64
+    }
+}
+
+pub struct SelectWithId {
+    id: u32,
+    envelope: AABB<[f64; 3]>,
+}
 
-        let sin_prod = self.pos.1.sin() * other_lat.sin();
-        let cos_prod = self.pos.1.cos() * other_lat.cos() * (self.pos.0 - other_lon).cos();
-        (sin_prod + cos_prod).acos() * 6371000.0
+impl SelectWithId {
+    // `envelope` is the one the provider was indexed with, only parents containing
+    // it need to be searched.
+    pub fn new(id: u32, envelope: AABB<[f64; 3]>) -> Self {
+        SelectWithId { id, envelope }
     }
+}
 
-    fn distance_2_if_less_or_equal(
-        &self,
-        point: &<Self::Envelope as Envelope>::Point,
-        max_distance_2: <<Self::Envelope as Envelope>::Point as Point>::Scalar,
-    ) -> Option<<<Self::Envelope as Envelope>::Point as Point>::Scalar> {
-        let dist = self.distance_2(point);
+impl SelectionFunction<InServiceProvider> for SelectWithId {
+    fn should_unpack_parent(&self, parent_envelope: &AABB<[f64; 3]>) -> bool {
+        parent_envelope.contains_envelope(&self.envelope)
+    }
 
-        if dist <= max_distance_2 {
-            Some(dist)
-        } else {
-            None
+    fn should_unpack_leaf(&self, leaf: &InServiceProvider) -> bool {
+        leaf.id == self.id
+    }
+}
+
+// How far past their own driving distance providers are still considered in range.
+#[derive(Debug, Clone)]
+pub enum Reach {
+    Group(PostcodeGroup),
+    // Extra metres
+    Radius(u64),
+}
+
+impl Reach {
+    pub fn extension(&self) -> u64 {
+        match self {
+            Reach::Group(group) => group.extension(),
+            Reach::Radius(extension) => *extension,
         }
     }
 }
 
-pub struct SelectWithId {
-    id: u32,
+// Selects the providers reaching `pos` when their driving distance is extended by `extension`.
+pub struct SelectInReach {
     pos: (f64, f64),
+    point: [f64; 3],
+    // Envelopes already cover the group extensions, a larger extension grows them by the
+    // chord of the difference.
+    growth: f64,
+    extension: u64,
 }
 
-impl SelectWithId {
-    pub fn new(id: u32, pos: (f64, f64)) -> Self {
-        SelectWithId { id, pos }
+impl SelectInReach {
+    // `indexed` is the extension the envelopes in the tree were grown by.
+    pub fn new(pos: (f64, f64), extension: u64, indexed: u64) -> Self {
+        let extra = extension.saturating_sub(indexed);
+        let angle = (extra as f64 / EARTH_RADIUS).min(PI);
+
+        SelectInReach {
+            pos,
+            point: unit_vector(pos),
+            growth: 2.0 * (angle / 2.0).sin(),
+            extension,
+        }
     }
 }
 
-impl SelectionFunction<InServiceProvider> for SelectWithId {
-    fn should_unpack_parent(
-        &self,
-        parent_envelope: &<InServiceProvider as RTreeObject>::Envelope,
-    ) -> bool {
-        parent_envelope.contains_point(&[self.pos.0, self.pos.1])
+impl SelectionFunction<InServiceProvider> for SelectInReach {
+    fn should_unpack_parent(&self, parent_envelope: &AABB<[f64; 3]>) -> bool {
+        let (lower, upper) = (parent_envelope.lower(), parent_envelope.upper());
+        (0..3).all(|axis| {
+            lower[axis] - self.growth <= self.point[axis]
+                && self.point[axis] <= upper[axis] + self.growth
+        })
     }
 
     fn should_unpack_leaf(&self, leaf: &InServiceProvider) -> bool {
-        leaf.id == self.id
+        match &leaf.area {
+            Some(area) => area.reaches(leaf.pos, self.pos, self.extension),
+            None => {
+                Map::calculate_distance(leaf.pos, self.pos)
+                    <= leaf.max_driving_distance.saturating_add(self.extension) as f64
+            }
+        }
     }
 }
 
+// A provider nearest to a location, whether or not it reaches that far.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Nearest {
+    pub id: u32,
+    pub name: String,
+    // Metres to the location
+    pub distance: f64,
+    pub max_driving_distance: u64,
+}
+
+// A provider just out of reach of a location, with how much further it would have to drive.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Suggestion {
+    #[serde(flatten)]
+    pub provider: ServiceProvider,
+    // Metres to the location in a straight line
+    pub distance: f64,
+    pub measured: Measured,
+    // Kilometres past the driving distance or service area and the group extension, rounded
+    // up to 100 m. Left out for travel times and where the road graph can't tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra_km: Option<f64>,
+    // Minutes past the travel time and the group extension by road, rounded up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra_minutes: Option<f64>,
+}
+
+// How the shortfall of a suggestion was measured.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Measured {
+    // Without a road graph and for drawn areas
+    Straight,
+    Road,
+    // The road graph has no route between the provider and the location
+    NoRoute,
+    // Either of them is too far from the road graph
+    OutsideGraph,
+}
+
+// How much further a provider would have to go to reach a location.
+enum Shortfall {
+    // Metres
+    Distance(Measured, f64),
+    // Seconds by road
+    Duration(f64),
+    Unknown(Measured),
+}
+
+// A postcode served by a provider, coordinates in degrees.
+#[derive(Serialize, Debug, Clone)]
+pub struct Coverage {
+    pub postcode: PostalCode,
+    pub group: PostcodeGroup,
+    // Metres from the provider to the postcode centre
+    pub distance: f64,
+    pub lon: f64,
+    pub lat: f64,
+}
+
 #[derive(Clone)]
 pub struct Map {
-    postcodes: HashMap<u32, Postcode>,
+    postcodes: HashMap<PostalCode, Postcode>,
     quality_factor: HashMap<u32, QualityFactor>,
     service_providers: HashMap<u32, ServiceProvider>,
-    a_tree: RTree<InServiceProvider>,
-    b_tree: RTree<InServiceProvider>,
-    c_tree: RTree<InServiceProvider>,
+    // Providers with their own driving distance, group extensions apply at query time
+    tree: RTree<InServiceProvider>,
+    store: Arc<dyn Store>,
+    // Largest group extension when the map was built, entries are indexed to cover it
+    max_extension: u64,
+    // None once the largest id is taken
+    next_id: Option<u32>,
+    ranking: RankingProfiles,
+    // Unix timestamp of the last API change per provider
+    updated_at: HashMap<u32, i64>,
+    // Driving distances replace straight lines for ranking when set
+    roads: Option<Arc<RoadGraph>>,
+    // Areas of the providers not serving a plain circle, isochrones need `roads`
+    areas: HashMap<u32, Arc<ServiceArea>>,
 }
 
 impl Map {
     pub fn new(
-        postcodes: HashMap<u32, Postcode>,
+        postcodes: HashMap<PostalCode, Postcode>,
         quality_factor: HashMap<u32, QualityFactor>,
         service_providers: HashMap<u32, ServiceProvider>,
     ) -> Self {
-        let converted_providers: Vec<ServiceProvider> =
-            service_providers.clone().into_values().collect();
-
-        let a_tree: RTree<InServiceProvider> = RTree::bulk_load(
-            converted_providers
-                .iter()
-                .map(|x| (*x).clone().into())
-                .collect(),
-        );
-
-        let b_tree: RTree<InServiceProvider> = RTree::bulk_load(
-            converted_providers
-                .iter()
-                .map(|x| {
-                    let mut service_provider: InServiceProvider = (*x).clone().into();
-                    service_provider.max_driving_distance += 2000;
-                    return service_provider;
-                })
-                .collect(),
-        );
-
-        let c_tree: RTree<InServiceProvider> = RTree::bulk_load(
-            converted_providers
-                .iter()
-                .map(|x| {
-                    let mut service_provider: InServiceProvider = (*x).clone().into();
-                    service_provider.max_driving_distance += 5000;
-                    return service_provider;
-                })
-                .collect(),
-        );
+        let next_id = service_providers
+            .keys()
+            .max()
+            .map_or(Some(1), |id| id.checked_add(1));
 
-        return Map {
+        let mut map = Map {
             postcodes,
             quality_factor,
             service_providers,
-            a_tree,
-            b_tree,
-            c_tree,
+            tree: RTree::new(),
+            store: Arc::new(MemoryStore),
+            max_extension: PostcodeGroup::max_extension(),
+            next_id,
+            ranking: RankingProfiles::default(),
+            updated_at: HashMap::new(),
+            roads: None,
+            areas: HashMap::new(),
         };
+
+        map.rebuild_index();
+        map
     }
 
-    fn calculate_distance(point_a: (f64, f64), point_b: (f64, f64)) -> f64 {
+    // Great-circle distance in metres between two (lon, lat) points in radians.
+    pub fn calculate_distance(point_a: (f64, f64), point_b: (f64, f64)) -> f64 {
         let sin_prod = point_b.1.sin() * point_a.1.sin();
         let cos_prod = point_b.1.cos() * point_a.1.cos() * (point_b.0 - point_a.0).cos();
-        (sin_prod + cos_prod).acos() * 6371000.0
+        // Rounding can push the cosine of (nearly) equal or antipodal points past ±1.
+        (sin_prod + cos_prod).clamp(-1.0, 1.0).acos() * EARTH_RADIUS
     }
 
-    fn calculate_rank(&self, point: (f64, f64), service_provider: &InServiceProvider) -> f64 {
-        let quality = self.quality_factor.get(&service_provider.id).unwrap();
-        let quality_factor =
-            0.6 * quality.profile_description_score + 0.4 * quality.profile_picture_score;
+    // The tree entry of a provider, with its service area if it has one.
+    fn index_entry(&self, provider: &ServiceProvider) -> InServiceProvider {
+        let entry = InServiceProvider::new(provider, self.max_extension);
 
-        let distance = Map::calculate_distance(point, service_provider.pos);
+        match self.areas.get(&provider.id) {
+            Some(area) => entry.with_area(Arc::clone(area), self.max_extension),
+            None => entry,
+        }
+    }
 
-        let default_distance = 80000.0;
-        let distance_score = 1.0 - (distance / default_distance);
-        let distance_weight = if distance > default_distance {
-            0.01
-        } else {
-            0.15
+    // Recomputes the service area of a provider, needs to happen before it is indexed.
+    fn update_area(&mut self, id: u32) {
+        let area = self
+            .service_providers
+            .get(&id)
+            .and_then(|provider| ServiceArea::of(provider, self.roads.as_deref()));
+
+        match area {
+            Some(area) => self.areas.insert(id, Arc::new(area)),
+            None => self.areas.remove(&id),
         };
+    }
 
-        distance_weight * distance_score + (1.0 - distance_weight) * quality_factor
+    fn insert_value(&mut self, id: u32) {
+        let entry = self.index_entry(&self.service_providers[&id]);
+        self.tree.insert(entry);
     }
 
-    fn drain_value(&mut self, id: u32) -> InServiceProvider {
-        let lat = self.service_providers[&id].lat;
-        let lon = self.service_providers[&id].lon;
+    fn drain_value(&mut self, id: u32) -> Option<InServiceProvider> {
+        let provider = self.service_providers.get(&id)?;
+        let envelope = self.index_entry(provider).envelope();
+
+        self.tree
+            .drain_with_selection_function(SelectWithId::new(id, envelope))
+            .next()
+    }
+
+    // Replays the mutations persisted in `store` and writes all further ones through to it.
+    pub fn attach_store(&mut self, store: Arc<dyn Store>) -> io::Result<usize> {
+        let entries = store.replay()?;
+        let applied = entries
+            .iter()
+            .filter(|entry| match entry.mutation.validate() {
+                Ok(()) => self.apply(entry),
+                Err(reason) => {
+                    eprintln!(
+                        "Skipping stored update of craftsman {}: {reason}.",
+                        entry.mutation.id()
+                    );
+                    false
+                }
+            })
+            .count();
+
+        if let Some(last) = entries.last() {
+            eprintln!(
+                "Replayed {applied} of {} stored updates, the latest from {}.",
+                entries.len(),
+                chrono::DateTime::from_timestamp(last.at, 0).unwrap_or_default()
+            );
+        }
+
+        self.store = store;
+        Ok(applied)
+    }
+
+    pub fn store(&self) -> Arc<dyn Store> {
+        Arc::clone(&self.store)
+    }
+
+    // Persists the mutation before applying it.
+    fn commit(&mut self, mutation: Mutation) -> io::Result<bool> {
+        let entry = Entry {
+            at: chrono::Utc::now().timestamp(),
+            mutation,
+        };
+
+        self.store.append(&entry)?;
+        Ok(self.apply(&entry))
+    }
 
-        self.b_tree
-            .drain_with_selection_function(SelectWithId::new(id, (lon, lat)));
+    // Applies a mutation to the in-memory state only. Returns false if its provider is unknown.
+    fn apply(&mut self, entry: &Entry) -> bool {
+        let applied = self.apply_mutation(&entry.mutation);
 
-        self.c_tree
-            .drain_with_selection_function(SelectWithId::new(id, (lon, lat)));
+        if applied {
+            let id = entry.mutation.id();
+            if matches!(entry.mutation, Mutation::Delete { .. }) {
+                self.updated_at.remove(&id);
+            } else {
+                self.updated_at.insert(id, entry.at);
+            }
+        }
 
-        self.a_tree
-            .drain_with_selection_function(SelectWithId::new(id, (lon, lat)))
-            .nth(0)
-            .unwrap()
+        applied
     }
 
+    fn apply_mutation(&mut self, mutation: &Mutation) -> bool {
+        match *mutation {
+            Mutation::Update {
+                id,
+                max_driving_distance,
+                max_travel_time,
+                profile_picture_score,
+                profile_description_score,
+            } => {
+                if !self.service_providers.contains_key(&id) {
+                    return false;
+                }
+
+                if max_driving_distance.is_some() || max_travel_time.is_some() {
+                    // The envelope depends on the service area, so the entry is rebuilt.
+                    self.drain_value(id);
+                    let service_provider = self.service_providers.get_mut(&id).unwrap();
+                    if let Some(distance) = max_driving_distance {
+                        service_provider.max_driving_distance = distance;
+                    }
+                    if let Some(minutes) = max_travel_time {
+                        service_provider.max_travel_time = (minutes > 0).then_some(minutes);
+                    }
+
+                    self.update_area(id);
+                    self.insert_value(id);
+                }
+
+                if let Some(quality) = self.quality_factor.get_mut(&id) {
+                    if let Some(score) = profile_picture_score {
+                        quality.profile_picture_score = score;
+                    }
+
+                    if let Some(score) = profile_description_score {
+                        quality.profile_description_score = score;
+                    }
+                }
+
+                true
+            }
+            Mutation::Create { id, ref provider } => {
+                if self.service_providers.contains_key(&id) {
+                    return false;
+                }
+
+                if self.next_id.is_some_and(|next| next <= id) {
+                    self.next_id = id.checked_add(1);
+                }
+                let (service_provider, quality) = provider.clone().into_parts(id);
+                self.service_providers.insert(id, service_provider);
+                self.quality_factor.insert(id, quality);
+                self.update_area(id);
+                self.insert_value(id);
+                true
+            }
+            Mutation::Relocate { id, ref address } => {
+                if !self.service_providers.contains_key(&id) {
+                    return false;
+                }
+
+                self.drain_value(id);
+                let service_provider = self.service_providers.get_mut(&id).unwrap();
+                service_provider.set_address(address.clone());
+
+                self.update_area(id);
+                self.insert_value(id);
+                true
+            }
+            Mutation::Areas {
+                id,
+                ref service_area,
+                ref excluded_areas,
+            } => {
+                if !self.service_providers.contains_key(&id) {
+                    return false;
+                }
+
+                self.drain_value(id);
+                let service_provider = self.service_providers.get_mut(&id).unwrap();
+                service_provider.service_area = service_area.clone();
+                service_provider.excluded_areas = excluded_areas.clone();
+
+                self.update_area(id);
+                self.insert_value(id);
+                true
+            }
+            Mutation::Delete { id } => {
+                if !self.service_providers.contains_key(&id) {
+                    return false;
+                }
+
+                self.drain_value(id);
+                self.service_providers.remove(&id);
+                self.quality_factor.remove(&id);
+                self.areas.remove(&id);
+                true
+            }
+        }
+    }
+
+    // Onboards a provider under a fresh id and returns it. Ids of deleted providers are not
+    // reused, so this fails once the largest id is taken.
+    pub fn create_service_provider(
+        &mut self,
+        provider: NewServiceProvider,
+    ) -> io::Result<ServiceProvider> {
+        let id = self
+            .next_id
+            .ok_or_else(|| io::Error::other("No craftsman ids are left."))?;
+        if !self.commit(Mutation::Create { id, provider })? {
+            return Err(io::Error::other(format!("Craftsman {id} already exists.")));
+        }
+
+        Ok(self.service_providers[&id].clone())
+    }
+
+    // Replaces address and coordinates. Returns None if there is no such provider.
+    pub fn relocate_service_provider(
+        &mut self,
+        id: u32,
+        address: Address,
+    ) -> io::Result<Option<ServiceProvider>> {
+        if !self.service_providers.contains_key(&id) {
+            return Ok(None);
+        }
+
+        self.commit(Mutation::Relocate { id, address })?;
+
+        Ok(self.service_providers.get(&id).cloned())
+    }
+
+    // Replaces the drawn service area and excluded zones. Returns None if there is no such
+    // provider.
+    pub fn set_service_areas(
+        &mut self,
+        id: u32,
+        service_area: Option<Geometry>,
+        excluded_areas: Vec<Geometry>,
+    ) -> io::Result<Option<ServiceProvider>> {
+        if !self.service_providers.contains_key(&id) {
+            return Ok(None);
+        }
+
+        self.commit(Mutation::Areas {
+            id,
+            service_area,
+            excluded_areas,
+        })?;
+
+        Ok(self.service_providers.get(&id).cloned())
+    }
+
+    // Offboards a provider. Returns false if there is no such provider.
+    pub fn delete_service_provider(&mut self, id: u32) -> io::Result<bool> {
+        if !self.service_providers.contains_key(&id) {
+            return Ok(false);
+        }
+
+        self.commit(Mutation::Delete { id })
+    }
+
+    // Persists the update before applying it. Returns None if there is no such provider.
     pub fn update_service_provider(
         &mut self,
         id: u32,
         driving_distance: Option<u64>,
+        travel_time: Option<u64>,
         picture_score: Option<f64>,
         description_score: Option<f64>,
-    ) -> (u64, f64, f64) {
-        if let Some(distance) = driving_distance {
-            let mut old = self.drain_value(id);
-            old.max_driving_distance = distance;
-            self.a_tree.insert(old.clone());
+    ) -> io::Result<Option<(ServiceProvider, QualityFactor)>> {
+        if !self.service_providers.contains_key(&id) {
+            return Ok(None);
+        }
 
-            old.max_driving_distance += 2000;
-            self.b_tree.insert(old.clone());
+        self.commit(Mutation::Update {
+            id,
+            max_driving_distance: driving_distance,
+            max_travel_time: travel_time,
+            profile_picture_score: picture_score,
+            profile_description_score: description_score,
+        })?;
 
-            old.max_driving_distance += 3000;
-            self.c_tree.insert(old);
+        Ok(Some((
+            self.service_providers[&id].clone(),
+            self.quality_factor[&id].clone(),
+        )))
+    }
 
+    pub fn set_ranking_profiles(&mut self, profiles: RankingProfiles) {
+        self.ranking = profiles;
+    }
+
+    // Providers with a travel time get their isochrones from the graph, so the whole
+    // index is rebuilt.
+    pub fn set_road_graph(&mut self, roads: Arc<RoadGraph>) {
+        self.roads = Some(roads);
+        self.rebuild_index();
+    }
+
+    // Computes all service areas and loads the tree from scratch.
+    fn rebuild_index(&mut self) {
+        let roads = self.roads.as_deref();
+        self.areas = self
+            .service_providers
+            .values()
+            .filter_map(|provider| Some((provider.id, Arc::new(ServiceArea::of(provider, roads)?))))
+            .collect();
+
+        self.tree = RTree::bulk_load(
             self.service_providers
-                .get_mut(&id)
-                .unwrap()
-                .max_driving_distance = distance;
-        }
+                .values()
+                .map(|provider| self.index_entry(provider))
+                .collect(),
+        );
+    }
 
-        if let Some(score) = picture_score {
-            self.quality_factor
-                .get_mut(&id)
-                .unwrap()
-                .profile_picture_score = score;
-        }
+    pub fn ranking_profile(&self, name: Option<&str>) -> Option<&RankingConfig> {
+        self.ranking.get(name)
+    }
 
-        if let Some(score) = description_score {
-            self.quality_factor
-                .get_mut(&id)
-                .unwrap()
-                .profile_description_score = score;
-        }
+    pub fn ranking_profile_names(&self) -> Vec<&str> {
+        self.ranking.names()
+    }
+
+    pub fn postcode_count(&self) -> usize {
+        self.postcodes.len()
+    }
 
-        (
-            self.service_providers[&id].max_driving_distance,
-            self.quality_factor[&id].profile_picture_score,
-            self.quality_factor[&id].profile_description_score,
-        )
+    pub fn service_provider_count(&self) -> usize {
+        self.service_providers.len()
     }
 
     pub fn service_provider_by_id(&self, id: u32) -> Option<ServiceProvider> {
         self.service_providers.get(&id).cloned()
     }
 
-    fn get_service_providers(&self, postcode: u32) -> Option<Vec<InServiceProvider>> {
-        if let Some(code) = self.postcodes.get(&postcode) {
-            let tree = match code.postcode_extension_distance_group {
-                PostcodeGroup::GroupA => &self.a_tree,
-                PostcodeGroup::GroupB => &self.b_tree,
-                PostcodeGroup::GroupC => &self.c_tree,
+    // Postcodes whose centre the provider reaches with the extension of the postcode's
+    // group, closest first. None if there is no such provider.
+    pub fn coverage(&self, id: u32) -> Option<Vec<Coverage>> {
+        let entry = self.index_entry(self.service_providers.get(&id)?);
+
+        let mut covered: Vec<Coverage> = self
+            .postcodes
+            .values()
+            .filter_map(|code| {
+                let group = &code.postcode_extension_distance_group;
+                let point = (code.lon, code.lat);
+                let distance = Map::calculate_distance(entry.pos, point);
+
+                let extension = group.extension();
+                let serves = SelectInReach::new(point, extension, self.max_extension)
+                    .should_unpack_leaf(&entry)
+                    && self.routes(point, slice::from_ref(&entry), extension)[0].is_some();
+
+                serves.then(|| Coverage {
+                    postcode: code.postcode,
+                    group: group.clone(),
+                    distance,
+                    lon: code.lon.to_degrees(),
+                    lat: code.lat.to_degrees(),
+                })
+            })
+            .collect();
+
+        covered.sort_by(|a, b| {
+            a.distance
+                .total_cmp(&b.distance)
+                .then(a.postcode.cmp(&b.postcode))
+        });
+        Some(covered)
+    }
+
+    // Number of providers serving each postcode.
+    pub fn provider_counts(&self) -> Vec<(PostalCode, PostcodeGroup, usize)> {
+        self.postcodes
+            .values()
+            .map(|code| {
+                let group = code.postcode_extension_distance_group.clone();
+                let count = self
+                    .serving((code.lon, code.lat), Reach::Group(group.clone()))
+                    .len();
+                (code.postcode, group, count)
+            })
+            .collect()
+    }
+
+    fn providers_at(&self, point: (f64, f64), reach: Reach) -> Vec<InServiceProvider> {
+        let extension = reach.extension();
+
+        self.tree
+            .locate_with_selection_function(SelectInReach::new(
+                point,
+                extension,
+                self.max_extension,
+            ))
+            .cloned()
+            .collect()
+    }
+
+    // The providers serving `point`, with their route there. Roads are never shorter than
+    // the great circle, so the tree still preselects.
+    fn serving(&self, point: (f64, f64), reach: Reach) -> Vec<(InServiceProvider, Route)> {
+        let extension = reach.extension();
+        let in_range = self.providers_at(point, reach);
+        let routes = self.routes(point, &in_range, extension);
+
+        in_range
+            .into_iter()
+            .zip(routes)
+            .filter_map(|(x, route)| Some((x, route?)))
+            .collect()
+    }
+
+    // The `k` providers closest to `point`, ignoring how far they are willing to drive.
+    pub fn nearest(&self, point: (f64, f64), k: usize) -> Vec<Nearest> {
+        self.tree
+            .nearest_neighbor_iter_with_distance_2(&unit_vector(point))
+            .take(k)
+            .map(|(provider, chord_2)| Nearest {
+                id: provider.id,
+                name: provider.name.clone(),
+                distance: chord_2_to_distance(chord_2),
+                max_driving_distance: provider.max_driving_distance,
+            })
+            .collect()
+    }
+
+    // Same as `nearest`, around the centre of `postcode`.
+    pub fn nearest_to_postcode(&self, postcode: PostalCode, k: usize) -> Option<Vec<Nearest>> {
+        let code = self.postcodes.get(&postcode)?;
+        Some(self.nearest((code.lon, code.lat), k))
+    }
+
+    // The `k` providers closest to `postcode` that don't reach it, even with the extension
+    // of its group, closest first. Empty if anybody serves the postcode, whatever a search
+    // filtered out.
+    pub fn suggestions(&self, postcode: PostalCode, k: usize) -> Option<Vec<Suggestion>> {
+        let code = self.postcodes.get(&postcode)?;
+        let point = (code.lon, code.lat);
+        let extension = code.postcode_extension_distance_group.extension();
+
+        let group = code.postcode_extension_distance_group.clone();
+        if !self.serving(point, Reach::Group(group)).is_empty() {
+            return Some(Vec::new());
+        }
+
+        let mut nearest = self
+            .tree
+            .nearest_neighbor_iter_with_distance_2(&unit_vector(point))
+            .map(|(provider, chord_2)| (provider.clone(), chord_2_to_distance(chord_2)));
+        let mut suggestions = Vec::with_capacity(k);
+
+        // Routed in batches, as some turn out to lie in excluded zones.
+        while suggestions.len() < k {
+            let (providers, distances): (Vec<InServiceProvider>, Vec<f64>) =
+                nearest.by_ref().take(k - suggestions.len()).unzip();
+            if providers.is_empty() {
+                break;
+            }
+
+            let shortfalls = self.shortfalls(point, &providers, extension);
+            for ((provider, distance), shortfall) in providers.iter().zip(distances).zip(shortfalls)
+            {
+                let (measured, extra_km, extra_minutes) = match shortfall {
+                    Some(Shortfall::Distance(measured, metres)) if metres > 0.0 => {
+                        (measured, Some((metres / 100.0).ceil() / 10.0), None)
+                    }
+                    Some(Shortfall::Duration(seconds)) if seconds > 0.0 => {
+                        (Measured::Road, None, Some((seconds / 60.0).ceil()))
+                    }
+                    Some(Shortfall::Unknown(measured)) => (measured, None, None),
+                    _ => continue,
+                };
+
+                suggestions.push(Suggestion {
+                    provider: self.service_providers[&provider.id].clone(),
+                    distance,
+                    measured,
+                    extra_km,
+                    extra_minutes,
+                });
+            }
+        }
+
+        Some(suggestions)
+    }
+
+    // How far `point` lies beyond the reach of each provider plus `extension`, by road
+    // where `routes` would take the road. None in excluded zones.
+    fn shortfalls(
+        &self,
+        point: (f64, f64),
+        providers: &[InServiceProvider],
+        extension: u64,
+    ) -> Vec<Option<Shortfall>> {
+        let straight = |x: &InServiceProvider| {
+            let metres = match &x.area {
+                Some(area) => area.shortfall(x.pos, point, extension)?,
+                None => {
+                    Map::calculate_distance(x.pos, point)
+                        - x.max_driving_distance.saturating_add(extension) as f64
+                }
             };
+            Some(Shortfall::Distance(Measured::Straight, metres))
+        };
 
-            let in_range: Vec<InServiceProvider> = tree
-                .locate_all_at_point(&[code.lon, code.lat])
-                .cloned()
+        let Some(roads) = &self.roads else {
+            return providers.iter().map(straight).collect();
+        };
+
+        let budgets: Vec<Option<(Cost, f64)>> = providers
+            .iter()
+            .map(|x| road_budget(x, extension))
+            .collect();
+        // Excluded zones stay closed whatever the road, the others are routed.
+        let mut shortfalls: Vec<Option<Shortfall>> = providers.iter().map(straight).collect();
+
+        for cost in [Cost::Distance, Cost::Duration] {
+            let selected: Vec<(usize, f64)> = budgets
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| shortfalls[*i].is_some())
+                .filter_map(|(i, budget)| match budget {
+                    Some((c, limit)) if *c == cost => Some((i, *limit)),
+                    _ => None,
+                })
                 .collect();
-            return Some(in_range);
-        } else {
-            None
+            if selected.is_empty() {
+                continue;
+            }
+
+            // However far, the search stops once all of them are found.
+            let origins: Vec<(f64, f64)> =
+                selected.iter().map(|(i, _)| providers[*i].pos).collect();
+            let routed = roads.routes_to(point, &origins, cost, f64::INFINITY);
+            for ((i, limit), routed) in selected.into_iter().zip(routed) {
+                shortfalls[i] = Some(match (routed, cost) {
+                    (Routed::Found(route), Cost::Distance) => {
+                        Shortfall::Distance(Measured::Road, route.distance - limit)
+                    }
+                    (Routed::Found(route), Cost::Duration) => {
+                        Shortfall::Duration(route.duration - limit)
+                    }
+                    (Routed::NotFound, _) => Shortfall::Unknown(Measured::NoRoute),
+                    (Routed::OutsideGraph, _) => Shortfall::Unknown(Measured::OutsideGraph),
+                });
+            }
         }
+
+        shortfalls
     }
 
-    pub fn ranked_by_score(&self, postcode: u32) -> Option<Vec<ServiceProviderView>> {
-        if let Some(code) = self.postcodes.get(&postcode) {
-            if let Some(in_range) = self.get_service_providers(postcode) {
-                let mut ranked: Vec<ServiceProviderView> = in_range
-                    .into_iter()
-                    .map(|x| ServiceProviderView {
-                        id: x.id,
-                        rankingScore: self.calculate_rank((code.lon, code.lat), &x),
-                        name: x.name,
-                    })
-                    .collect();
+    // Ranks the providers serving `postcode` that pass `filter` by `keys`, ties broken by id.
+    // Ages of updates are taken at `now`, a Unix timestamp.
+    pub fn ranked(
+        &self,
+        postcode: PostalCode,
+        keys: &[SortKey],
+        filter: &Filter,
+        ranking: &RankingConfig,
+        now: i64,
+    ) -> Option<Vec<Ranked>> {
+        let code = self.postcodes.get(&postcode)?;
 
-                ranked.sort_by(|a, b| b.rankingScore.total_cmp(&a.rankingScore));
-                return Some(ranked);
+        Some(self.ranked_at(
+            (code.lon, code.lat),
+            Reach::Group(code.postcode_extension_distance_group.clone()),
+            keys,
+            filter,
+            ranking,
+            now,
+        ))
+    }
+
+    // Driving distance and time from each provider to `point`, None for the ones that can't
+    // reach it by road within their driving distance or travel time plus `extension`. Without a road graph,
+    // or where it doesn't cover both ends, the straight line is taken.
+    fn routes(
+        &self,
+        point: (f64, f64),
+        providers: &[InServiceProvider],
+        extension: u64,
+    ) -> Vec<Option<Route>> {
+        let straight = |x: &InServiceProvider| {
+            let distance = Map::calculate_distance(x.pos, point);
+            Route {
+                distance,
+                duration: estimate_duration(distance),
+            }
+        };
+
+        let Some(roads) = &self.roads else {
+            return providers.iter().map(|x| Some(straight(x))).collect();
+        };
+
+        let budgets: Vec<Option<(Cost, f64)>> = providers
+            .iter()
+            .map(|x| road_budget(x, extension))
+            .collect();
+
+        let mut routes: Vec<Option<Route>> = providers
+            .iter()
+            .zip(&budgets)
+            .map(|(x, budget)| budget.is_none().then(|| straight(x)))
+            .collect();
+
+        for cost in [Cost::Distance, Cost::Duration] {
+            let selected: Vec<(usize, f64)> = budgets
+                .iter()
+                .enumerate()
+                .filter_map(|(i, budget)| match budget {
+                    Some((c, limit)) if *c == cost => Some((i, *limit)),
+                    _ => None,
+                })
+                .collect();
+            if selected.is_empty() {
+                continue;
+            }
+
+            let limit = selected.iter().map(|(_, limit)| *limit).fold(0.0, f64::max);
+            let origins: Vec<(f64, f64)> =
+                selected.iter().map(|(i, _)| providers[*i].pos).collect();
+
+            let routed = roads.routes_to(point, &origins, cost, limit);
+            for ((i, limit), routed) in selected.into_iter().zip(routed) {
+                routes[i] = match routed {
+                    Routed::Found(route) => (cost.of(&route) <= limit).then_some(route),
+                    Routed::NotFound => None,
+                    Routed::OutsideGraph => Some(straight(&providers[i])),
+                };
             }
         }
 
-        return None;
+        routes
     }
 
-    pub fn ranked_by_distance(&self, postcode: u32) -> Option<Vec<ServiceProviderView>> {
-        if let Some(code) = self.postcodes.get(&postcode) {
-            if let Some(in_range) = self.get_service_providers(postcode) {
-                let mut ranked: Vec<ServiceProviderView> = in_range
-                    .into_iter()
-                    .map(|x| ServiceProviderView {
-                        id: x.id,
-                        rankingScore: Map::calculate_distance(x.pos, (code.lon, code.lat)),
-                        name: x.name,
-                    })
+    // Same as `ranked`, for any point given as (lon, lat) in radians.
+    pub fn ranked_at(
+        &self,
+        point: (f64, f64),
+        reach: Reach,
+        keys: &[SortKey],
+        filter: &Filter,
+        ranking: &RankingConfig,
+        now: i64,
+    ) -> Vec<Ranked> {
+        let mut scored: Vec<(Vec<f64>, InServiceProvider)> = self
+            .serving(point, reach)
+            .into_iter()
+            .filter_map(|(x, route)| {
+                let candidate = Candidate {
+                    distance: route.distance,
+                    duration: route.duration,
+                    quality: &self.quality_factor[&x.id],
+                    age: self.updated_at.get(&x.id).map(|at| now - at),
+                };
+
+                if !filter.matches(&self.service_providers[&x.id], &candidate, ranking) {
+                    return None;
+                }
+
+                let scores = keys
+                    .iter()
+                    .map(|key| key.ranker.score(&candidate, ranking))
                     .collect();
+                Some((scores, x))
+            })
+            .collect();
 
-                ranked.sort_by(|a, b| a.rankingScore.total_cmp(&b.rankingScore));
-                return Some(ranked);
-            }
+        scored.sort_by(|(a_scores, a), (b_scores, b)| {
+            compare_scores(keys, a_scores, b_scores).then(a.id.cmp(&b.id))
+        });
+
+        scored
+            .into_iter()
+            .map(|(scores, x)| Ranked {
+                id: x.id,
+                name: x.name,
+                scores,
+            })
+            .collect()
+    }
+}
+
+// Searches and nearest neighbours on the spatial index compared with a scan over all
+// providers, for providers and points spread over the whole globe.
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::{FRAC_PI_2, PI};
+
+    use super::*;
+    use crate::geo::{self, Geometry, Polygon};
+    use crate::ranking::{DistanceRanker, RankingConfig};
+    use crate::search::{Filter, SortKey};
+    use crate::synthetic::{self, position, Rng};
+
+    const PROVIDERS: u32 = 2000;
+    const QUERIES: u32 = 300;
+
+    // A random position anywhere on earth in radians. A third of them lie close to a pole
+    // and a third close to the antimeridian, where envelopes are hardest to get right.
+    fn world_position(rng: &mut Rng) -> (f64, f64) {
+        let side = if rng.next() % 2 == 0 { 1.0 } else { -1.0 };
+
+        let lon = match rng.next() % 3 {
+            0 => side * (PI - rng.range(0.0, 0.1)),
+            _ => rng.range(-PI, PI),
+        };
+        let lat = match rng.next() % 3 {
+            0 => side * (FRAC_PI_2 - rng.range(0.0, 0.15)),
+            _ => rng.range(-1.0, 1.0).asin(),
+        };
+
+        (lon, lat)
+    }
+
+    // Id, position, driving distance and drawn area of each provider
+    type Positions = Vec<(u32, (f64, f64), u64, Option<Polygon>)>;
+
+    // A square of about 60 km in Germany, usually nowhere near the provider drawing it.
+    fn drawn_area(rng: &mut Rng) -> Polygon {
+        let (lon, lat) = position(rng);
+        let (lon, lat) = (lon.to_degrees(), lat.to_degrees());
+        let ring = [
+            (-1.0, -1.0),
+            (1.0, -1.0),
+            (1.0, 1.0),
+            (-1.0, 1.0),
+            (-1.0, -1.0),
+        ]
+        .map(|(x, y)| [lon + 0.4 * x, lat + 0.3 * y]);
+
+        vec![ring.to_vec()]
+    }
+
+    // Mostly regional distances, some reaching around half the globe. One in ten
+    // providers serves a drawn area instead.
+    fn world_map(rng: &mut Rng) -> (Map, Positions) {
+        let (mut provider_data, quality) = synthetic::providers(rng, PROVIDERS, |rng| {
+            let distance = if rng.next() % 20 == 0 {
+                rng.range(1000.0, 25000000.0)
+            } else {
+                rng.range(1000.0, 2000000.0)
+            };
+            (world_position(rng), distance as u64)
+        });
+
+        let mut positions = Vec::new();
+        for id in 1..=PROVIDERS {
+            let provider = provider_data.get_mut(&id).unwrap();
+            let area = (rng.next() % 10 == 0).then(|| drawn_area(rng));
+            provider.service_area = area
+                .clone()
+                .map(|coordinates| Geometry::Polygon { coordinates });
+            positions.push((
+                id,
+                (provider.lon, provider.lat),
+                provider.max_driving_distance,
+                area,
+            ));
         }
 
-        return None;
+        (Map::new(HashMap::new(), quality, provider_data), positions)
     }
 
-    pub fn ranked_by_profile(&self, postcode: u32) -> Option<Vec<ServiceProviderView>> {
-        if let Some(in_range) = self.get_service_providers(postcode) {
-            let mut ranked: Vec<ServiceProviderView> = in_range
+    #[test]
+    fn search_matches_scan() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        let (map, positions) = world_map(&mut rng);
+        let ranking = RankingConfig::default();
+        let keys = [SortKey::natural(&DistanceRanker)];
+
+        for _ in 0..QUERIES {
+            // Every other one in Germany, where the drawn areas are
+            let point = if rng.next() % 2 == 0 {
+                position(&mut rng)
+            } else {
+                world_position(&mut rng)
+            };
+            let extension = [0, 2000, 5000, 50000][(rng.next() % 4) as usize];
+            let degrees = [point.0.to_degrees(), point.1.to_degrees()];
+
+            let mut found: Vec<u32> = map
+                .ranked_at(
+                    point,
+                    Reach::Radius(extension),
+                    &keys,
+                    &Filter::default(),
+                    &ranking,
+                    0,
+                )
                 .into_iter()
-                .map(|x| {
-                    let quality = self.quality_factor.get(&x.id).unwrap();
-                    ServiceProviderView {
-                        id: x.id,
-                        rankingScore: 0.6 * quality.profile_description_score
-                            + 0.4 * quality.profile_picture_score,
-                        name: x.name,
+                .map(|ranked| ranked.id)
+                .collect();
+            found.sort();
+
+            let mut expected: Vec<u32> = positions
+                .iter()
+                .filter(|(_, pos, distance, area)| match area {
+                    Some(area) => {
+                        geo::polygon_contains(area, degrees)
+                            || geo::distance_to_polygon(area, degrees) <= extension as f64
                     }
+                    None => Map::calculate_distance(*pos, point) <= (distance + extension) as f64,
                 })
+                .map(|(id, _, _, _)| *id)
                 .collect();
+            expected.sort();
 
-            ranked.sort_by(|a, b| b.rankingScore.total_cmp(&a.rankingScore));
-            return Some(ranked);
+            assert_eq!(
+                found,
+                expected,
+                "at ({:.4}, {:.4}) +{extension} m",
+                point.0.to_degrees(),
+                point.1.to_degrees()
+            );
         }
+    }
 
-        return None;
+    // Nearest neighbours have to come in the order of a full sort by distance.
+    #[test]
+    fn nearest_matches_sort() {
+        const K: usize = 10;
+
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        let (map, positions) = world_map(&mut rng);
+
+        for _ in 0..QUERIES {
+            let point = world_position(&mut rng);
+            let nearest: Vec<f64> = map
+                .nearest(point, K)
+                .iter()
+                .map(|nearest| nearest.distance)
+                .collect();
+            let mut distances: Vec<f64> = positions
+                .iter()
+                .map(|(_, pos, _, _)| Map::calculate_distance(*pos, point))
+                .collect();
+            distances.sort_by(f64::total_cmp);
+            distances.truncate(K);
+
+            let close = nearest.len() == distances.len()
+                && nearest
+                    .iter()
+                    .zip(&distances)
+                    .all(|(a, b)| (a - b).abs() < 0.01);
+            assert!(
+                close,
+                "at ({:.4}, {:.4}): {nearest:?} instead of {distances:?}",
+                point.0.to_degrees(),
+                point.1.to_degrees()
+            );
+        }
     }
 }
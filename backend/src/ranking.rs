@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::QualityFactor;
+use crate::import;
+use crate::validation::{Invalid, LoadError};
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+// Weights of the ranking formula. Missing fields in a config file fall back to the
+// defaults below, so profiles only need to list what they change.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RankingConfig {
+    pub description_weight: f64,
+    pub picture_weight: f64,
+    // Distance in metres at which the distance score drops to zero
+    pub default_distance: f64,
+    pub distance_weight: f64,
+    // Distance weight for providers further away than `default_distance`
+    pub far_distance_weight: f64,
+    // Days after which the freshness score of an update has halved
+    pub freshness_half_life_days: f64,
+}
+
+impl Default for RankingConfig {
+    fn default() -> Self {
+        RankingConfig {
+            description_weight: 0.6,
+            picture_weight: 0.4,
+            default_distance: 80000.0,
+            distance_weight: 0.15,
+            far_distance_weight: 0.01,
+            freshness_half_life_days: 30.0,
+        }
+    }
+}
+
+impl RankingConfig {
+    // Both are divided by, so anything but a positive number breaks every score.
+    pub fn validate(&self) -> Result<(), Invalid> {
+        let divisors = [
+            ("default_distance", self.default_distance),
+            ("freshness_half_life_days", self.freshness_half_life_days),
+        ];
+        match divisors
+            .into_iter()
+            .find(|(_, value)| !value.is_finite() || *value <= 0.0)
+        {
+            Some((name, value)) => Err(Invalid::NotPositive(name, value)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn quality(&self, quality: &QualityFactor) -> f64 {
+        self.description_weight * quality.profile_description_score
+            + self.picture_weight * quality.profile_picture_score
+    }
+
+    pub fn score(&self, quality: &QualityFactor, distance: f64) -> f64 {
+        let distance_score = 1.0 - (distance / self.default_distance);
+        let distance_weight = if distance > self.default_distance {
+            self.far_distance_weight
+        } else {
+            self.distance_weight
+        };
+
+        distance_weight * distance_score + (1.0 - distance_weight) * self.quality(quality)
+    }
+}
+
+// Named weight profiles, selectable per request. There always is a "default" one.
+#[derive(Debug, Clone)]
+pub struct RankingProfiles {
+    profiles: HashMap<String, RankingConfig>,
+}
+
+impl Default for RankingProfiles {
+    fn default() -> Self {
+        RankingProfiles::new(HashMap::new())
+    }
+}
+
+impl RankingProfiles {
+    pub fn new(mut profiles: HashMap<String, RankingConfig>) -> Self {
+        profiles.entry(DEFAULT_PROFILE.to_string()).or_default();
+        RankingProfiles { profiles }
+    }
+
+    // Reads a JSON object mapping profile names to their weights.
+    pub fn from_file(path: &Path) -> Result<Self, LoadError> {
+        let dataset = "ranking config";
+        let profiles: HashMap<String, RankingConfig> = import::read_config(path, dataset)?;
+
+        for (name, config) in &profiles {
+            config.validate().map_err(|reason| LoadError::Invalid {
+                dataset,
+                id: format!("profile `{name}`"),
+                reason,
+            })?;
+        }
+
+        Ok(RankingProfiles::new(profiles))
+    }
+
+    pub fn get(&self, name: Option<&str>) -> Option<&RankingConfig> {
+        self.profiles.get(name.unwrap_or(DEFAULT_PROFILE))
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+}
+
+// A provider in range of the searched location, with everything rankers may look at.
+pub struct Candidate<'a> {
+    // Metres to drive to the searched location, the great-circle distance without a road graph
+    pub distance: f64,
+    // Seconds to drive there, estimated from the distance without a road graph
+    pub duration: f64,
+    pub quality: &'a QualityFactor,
+    // Seconds since the provider was last changed through the API
+    pub age: Option<i64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+pub trait Ranker: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    // Whether lower or higher scores come first.
+    fn order(&self) -> Order {
+        Order::Descending
+    }
+
+    fn score(&self, candidate: &Candidate, config: &RankingConfig) -> f64;
+}
+
+pub struct ScoreRanker;
+
+impl Ranker for ScoreRanker {
+    fn name(&self) -> &'static str {
+        "score"
+    }
+
+    fn description(&self) -> &'static str {
+        "Profile quality combined with distance"
+    }
+
+    fn score(&self, candidate: &Candidate, config: &RankingConfig) -> f64 {
+        config.score(candidate.quality, candidate.distance)
+    }
+}
+
+pub struct DistanceRanker;
+
+impl Ranker for DistanceRanker {
+    fn name(&self) -> &'static str {
+        "distance"
+    }
+
+    fn description(&self) -> &'static str {
+        "Closest craftsmen first, score is the distance in metres"
+    }
+
+    fn order(&self) -> Order {
+        Order::Ascending
+    }
+
+    fn score(&self, candidate: &Candidate, _config: &RankingConfig) -> f64 {
+        candidate.distance
+    }
+}
+
+pub struct DurationRanker;
+
+impl Ranker for DurationRanker {
+    fn name(&self) -> &'static str {
+        "duration"
+    }
+
+    fn description(&self) -> &'static str {
+        "Shortest drive first, score is the driving time in seconds"
+    }
+
+    fn order(&self) -> Order {
+        Order::Ascending
+    }
+
+    fn score(&self, candidate: &Candidate, _config: &RankingConfig) -> f64 {
+        candidate.duration
+    }
+}
+
+pub struct ProfileRanker;
+
+impl Ranker for ProfileRanker {
+    fn name(&self) -> &'static str {
+        "profile"
+    }
+
+    fn description(&self) -> &'static str {
+        "Profile picture and description quality only"
+    }
+
+    fn score(&self, candidate: &Candidate, config: &RankingConfig) -> f64 {
+        config.quality(candidate.quality)
+    }
+}
+
+pub struct FreshnessRanker;
+
+impl Ranker for FreshnessRanker {
+    fn name(&self) -> &'static str {
+        "freshness"
+    }
+
+    fn description(&self) -> &'static str {
+        "Recently updated profiles first, halving every freshness_half_life_days"
+    }
+
+    fn score(&self, candidate: &Candidate, config: &RankingConfig) -> f64 {
+        match candidate.age {
+            Some(age) => {
+                let days = age.max(0) as f64 / 86400.0;
+                0.5f64.powf(days / config.freshness_half_life_days)
+            }
+            None => 0.0,
+        }
+    }
+}
+
+// Ranking modes selectable with the `sort` parameter.
+pub struct Rankers {
+    rankers: Vec<Box<dyn Ranker>>,
+    aliases: HashMap<&'static str, &'static str>,
+}
+
+impl Default for Rankers {
+    fn default() -> Self {
+        let mut rankers = Rankers {
+            rankers: Vec::new(),
+            aliases: HashMap::new(),
+        };
+
+        rankers.register(ScoreRanker);
+        rankers.register(DistanceRanker);
+        rankers.register(DurationRanker);
+        rankers.register(ProfileRanker);
+        rankers.register(FreshnessRanker);
+        // The frontend has always asked for "rank".
+        rankers.alias("rank", "score");
+
+        rankers
+    }
+}
+
+impl Rankers {
+    pub fn register(&mut self, ranker: impl Ranker + 'static) {
+        self.rankers.retain(|r| r.name() != ranker.name());
+        self.rankers.push(Box::new(ranker));
+    }
+
+    pub fn alias(&mut self, alias: &'static str, name: &'static str) {
+        self.aliases.insert(alias, name);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Ranker> {
+        let name = self.aliases.get(name).copied().unwrap_or(name);
+
+        self.rankers
+            .iter()
+            .find(|r| r.name() == name)
+            .map(|r| r.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Ranker> {
+        self.rankers.iter().map(|r| r.as_ref())
+    }
+}
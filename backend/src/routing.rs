@@ -0,0 +1,426 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::path::Path;
+
+use rstar::primitives::GeomWithData;
+use rstar::RTree;
+use serde::Deserialize;
+
+use crate::import::{self, RecordError};
+use crate::map::{unit_vector, Map};
+use crate::validation::{Invalid, LoadError};
+
+// Speed in km/h of roads without one in the graph, also used to estimate travel times
+// where there is no route.
+pub const DEFAULT_SPEED: f64 = 50.0;
+
+// Speed in km/h between a location and the closest node of the graph.
+const ACCESS_SPEED: f64 = 30.0;
+
+// Locations further than this many metres from any node are outside the graph.
+const MAX_SNAP_DISTANCE: f64 = 5000.0;
+
+// Seconds needed for `distance` metres at `speed` km/h.
+fn travel_time(distance: f64, speed: f64) -> f64 {
+    distance / (speed / 3.6)
+}
+
+// Seconds needed for `distance` metres when there is no road to follow.
+pub fn estimate_duration(distance: f64) -> f64 {
+    travel_time(distance, DEFAULT_SPEED)
+}
+
+// Metres covered in `duration` seconds when there is no road to follow.
+pub fn estimate_distance(duration: f64) -> f64 {
+    duration * (DEFAULT_SPEED / 3.6)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RoadNode {
+    // OSM node id or any other id unique within the file
+    pub id: u64,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RoadEdge {
+    pub from: u64,
+    pub to: u64,
+    // Metres, the straight line between both nodes if missing
+    pub length: Option<f64>,
+    // km/h
+    pub speed: Option<f64>,
+    #[serde(default)]
+    pub oneway: bool,
+}
+
+// Road graph file as exported from OSM, degrees like the other datasets.
+#[derive(Deserialize, Debug)]
+struct RoadGraphFile {
+    nodes: Vec<RoadNode>,
+    edges: Vec<RoadEdge>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    // The node at the other end
+    node: usize,
+    length: f64,
+    duration: f64,
+}
+
+// Edges per node in one array, `offsets[node]` being the first edge of `node`.
+struct Adjacency {
+    offsets: Vec<usize>,
+    edges: Vec<Edge>,
+}
+
+impl Adjacency {
+    fn new(lists: Vec<Vec<Edge>>) -> Self {
+        let mut offsets = Vec::with_capacity(lists.len() + 1);
+        offsets.push(0);
+        for edges in &lists {
+            offsets.push(offsets.last().unwrap() + edges.len());
+        }
+
+        Adjacency {
+            offsets,
+            edges: lists.into_iter().flatten().collect(),
+        }
+    }
+
+    fn of(&self, node: usize) -> &[Edge] {
+        &self.edges[self.offsets[node]..self.offsets[node + 1]]
+    }
+}
+
+// What a search minimises and limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cost {
+    Distance,
+    Duration,
+}
+
+impl Cost {
+    pub fn of(self, route: &Route) -> f64 {
+        match self {
+            Cost::Distance => route.distance,
+            Cost::Duration => route.duration,
+        }
+    }
+}
+
+// Driving distance in metres and time in seconds between two locations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Route {
+    pub distance: f64,
+    pub duration: f64,
+}
+
+// Outcome of routing from one location.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Routed {
+    Found(Route),
+    // No connection within the limit
+    NotFound,
+    // Either end is too far from any road of the graph to tell
+    OutsideGraph,
+}
+
+// Directed road network. Searches towards a location follow the incoming edges
+// backwards, searches from a location the outgoing ones.
+pub struct RoadGraph {
+    // (lon, lat) in radians
+    nodes: Vec<(f64, f64)>,
+    incoming: Adjacency,
+    outgoing: Adjacency,
+    index: RTree<GeomWithData<[f64; 3], usize>>,
+}
+
+impl RoadGraph {
+    pub fn new(nodes: Vec<RoadNode>, edges: Vec<RoadEdge>) -> Result<Self, RecordError> {
+        let mut ids = HashMap::with_capacity(nodes.len());
+        for (i, node) in nodes.iter().enumerate() {
+            if ids.insert(node.id, i).is_some() {
+                return Err(RecordError {
+                    line: None,
+                    record: Some(i as u64 + 1),
+                    field: Some("id".to_string()),
+                    message: format!("duplicate node {}", node.id),
+                });
+            }
+
+            let invalid = if !(-90.0..=90.0).contains(&node.lat) {
+                Some(("lat", Invalid::LatitudeOutOfRange(node.lat)))
+            } else if !(-180.0..=180.0).contains(&node.lon) {
+                Some(("lon", Invalid::LongitudeOutOfRange(node.lon)))
+            } else {
+                None
+            };
+            if let Some((field, reason)) = invalid {
+                return Err(RecordError {
+                    line: None,
+                    record: Some(i as u64 + 1),
+                    field: Some(field.to_string()),
+                    message: reason.to_string(),
+                });
+            }
+        }
+
+        let positions: Vec<(f64, f64)> = nodes
+            .iter()
+            .map(|node| (node.lon.to_radians(), node.lat.to_radians()))
+            .collect();
+
+        let mut incoming: Vec<Vec<Edge>> = vec![Vec::new(); nodes.len()];
+        let mut outgoing: Vec<Vec<Edge>> = vec![Vec::new(); nodes.len()];
+        for (i, edge) in edges.iter().enumerate() {
+            let node = |id: u64, field: &str| {
+                ids.get(&id).copied().ok_or_else(|| RecordError {
+                    line: None,
+                    record: Some(i as u64 + 1),
+                    field: Some(field.to_string()),
+                    message: format!("unknown node {id}"),
+                })
+            };
+            let (from, to) = (node(edge.from, "from")?, node(edge.to, "to")?);
+
+            let length = match edge.length {
+                Some(length) if length.is_finite() && length > 0.0 => length,
+                Some(length) => {
+                    return Err(RecordError {
+                        line: None,
+                        record: Some(i as u64 + 1),
+                        field: Some("length".to_string()),
+                        message: Invalid::NotPositive("length", length).to_string(),
+                    })
+                }
+                None => Map::calculate_distance(positions[from], positions[to]),
+            };
+            let speed = edge
+                .speed
+                .filter(|speed| speed.is_finite() && *speed > 0.0)
+                .unwrap_or(DEFAULT_SPEED);
+            let duration = travel_time(length, speed);
+
+            let mut connect = |from: usize, to: usize| {
+                outgoing[from].push(Edge {
+                    node: to,
+                    length,
+                    duration,
+                });
+                incoming[to].push(Edge {
+                    node: from,
+                    length,
+                    duration,
+                });
+            };
+            connect(from, to);
+            if !edge.oneway {
+                connect(to, from);
+            }
+        }
+
+        let index = RTree::bulk_load(
+            positions
+                .iter()
+                .enumerate()
+                .map(|(i, pos)| GeomWithData::new(unit_vector(*pos), i))
+                .collect(),
+        );
+
+        Ok(RoadGraph {
+            nodes: positions,
+            incoming: Adjacency::new(incoming),
+            outgoing: Adjacency::new(outgoing),
+            index,
+        })
+    }
+
+    // Reads a JSON object with `nodes` ({id, lat, lon}) and `edges` ({from, to, length,
+    // speed, oneway}) as exported from OSM.
+    pub fn from_file(path: &Path) -> Result<Self, LoadError> {
+        let dataset = "road graph";
+        let file: RoadGraphFile = import::read_config(path, dataset)?;
+
+        RoadGraph::new(file.nodes, file.edges).map_err(|error| LoadError::Record { dataset, error })
+    }
+
+    // The node closest to `pos` and the metres to it, None outside the graph.
+    fn snap(&self, pos: (f64, f64)) -> Option<(usize, f64)> {
+        let nearest = self.index.nearest_neighbor(&unit_vector(pos))?;
+        let distance = Map::calculate_distance(pos, self.nodes[nearest.data]);
+
+        (distance <= MAX_SNAP_DISTANCE).then_some((nearest.data, distance))
+    }
+
+    // Dijkstra search from `start` along `adjacency`, minimising `cost` up to `limit`.
+    // Stops early once all of `targets` are settled, unless there are none. Returns the
+    // settled nodes with their routes.
+    fn search(
+        &self,
+        adjacency: &Adjacency,
+        start: usize,
+        start_route: Route,
+        cost: Cost,
+        limit: f64,
+        mut targets: HashSet<usize>,
+    ) -> HashMap<usize, Route> {
+        let exhaustive = targets.is_empty();
+        let mut settled: HashMap<usize, Route> = HashMap::new();
+        let mut best: HashMap<usize, f64> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+
+        best.insert(start, cost.of(&start_route));
+        queue.push(State {
+            cost: cost.of(&start_route),
+            route: start_route,
+            node: start,
+        });
+
+        while let Some(State {
+            cost: at,
+            route,
+            node,
+        }) = queue.pop()
+        {
+            if at > limit || (!exhaustive && targets.is_empty()) {
+                break;
+            }
+            if settled.contains_key(&node) {
+                continue;
+            }
+            settled.insert(node, route);
+            targets.remove(&node);
+
+            for edge in adjacency.of(node) {
+                let next = Route {
+                    distance: route.distance + edge.length,
+                    duration: route.duration + edge.duration,
+                };
+                let next_cost = cost.of(&next);
+                if settled.contains_key(&edge.node)
+                    || next_cost > limit
+                    || best.get(&edge.node).is_some_and(|c| *c <= next_cost)
+                {
+                    continue;
+                }
+
+                best.insert(edge.node, next_cost);
+                queue.push(State {
+                    cost: next_cost,
+                    route: next,
+                    node: edge.node,
+                });
+            }
+        }
+
+        settled
+    }
+
+    // Shortest or fastest routes from each of `origins` to `destination`, all (lon, lat)
+    // in radians. Routes costing more than `limit` metres or seconds are not searched for.
+    pub fn routes_to(
+        &self,
+        destination: (f64, f64),
+        origins: &[(f64, f64)],
+        cost: Cost,
+        limit: f64,
+    ) -> Vec<Routed> {
+        let Some((target, target_access)) = self.snap(destination) else {
+            return vec![Routed::OutsideGraph; origins.len()];
+        };
+
+        let snapped: Vec<Option<(usize, f64)>> =
+            origins.iter().map(|origin| self.snap(*origin)).collect();
+        let targets: HashSet<usize> = snapped.iter().flatten().map(|(node, _)| *node).collect();
+        if targets.is_empty() {
+            return vec![Routed::OutsideGraph; origins.len()];
+        }
+
+        let start = Route {
+            distance: target_access,
+            duration: travel_time(target_access, ACCESS_SPEED),
+        };
+        let settled = self.search(&self.incoming, target, start, cost, limit, targets);
+
+        snapped
+            .into_iter()
+            .map(|snapped| {
+                let Some((node, access)) = snapped else {
+                    return Routed::OutsideGraph;
+                };
+                let Some(route) = settled.get(&node) else {
+                    return Routed::NotFound;
+                };
+
+                let route = Route {
+                    distance: route.distance + access,
+                    duration: route.duration + travel_time(access, ACCESS_SPEED),
+                };
+                if cost.of(&route) > limit {
+                    return Routed::NotFound;
+                }
+
+                Routed::Found(route)
+            })
+            .collect()
+    }
+
+    // Positions reachable from `origin` within `duration` seconds, [lon, lat] in degrees
+    // and including `origin` itself. None outside the graph.
+    pub fn reachable(&self, origin: (f64, f64), duration: f64) -> Option<Vec<[f64; 2]>> {
+        let (start, access) = self.snap(origin)?;
+        let start_route = Route {
+            distance: access,
+            duration: travel_time(access, ACCESS_SPEED),
+        };
+
+        let settled = self.search(
+            &self.outgoing,
+            start,
+            start_route,
+            Cost::Duration,
+            duration,
+            HashSet::new(),
+        );
+
+        let degrees = |(lon, lat): (f64, f64)| [lon.to_degrees(), lat.to_degrees()];
+        Some(
+            std::iter::once(degrees(origin))
+                .chain(settled.keys().map(|node| degrees(self.nodes[*node])))
+                .collect(),
+        )
+    }
+}
+
+// Queue entry, the lowest cost is popped first.
+struct State {
+    cost: f64,
+    route: Route,
+    node: usize,
+}
+
+impl PartialEq for State {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for State {}
+
+impl PartialOrd for State {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for State {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .cost
+            .total_cmp(&self.cost)
+            .then(other.node.cmp(&self.node))
+    }
+}
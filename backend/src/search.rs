@@ -0,0 +1,256 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::data::{PostalCode, ServiceProvider, ServiceProviderView};
+use crate::ranking::{Candidate, Order, Ranker, Rankers, RankingConfig};
+
+// Restricts the providers in range of a search.
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    pub min_quality: Option<f64>,
+    // Metres from the searched location
+    pub max_distance: Option<f64>,
+    pub city: Option<String>,
+    pub name: Option<String>,
+}
+
+impl Filter {
+    pub fn matches(
+        &self,
+        provider: &ServiceProvider,
+        candidate: &Candidate,
+        ranking: &RankingConfig,
+    ) -> bool {
+        if let Some(min_quality) = self.min_quality {
+            if ranking.quality(candidate.quality) < min_quality {
+                return false;
+            }
+        }
+
+        if let Some(max_distance) = self.max_distance {
+            if candidate.distance > max_distance {
+                return false;
+            }
+        }
+
+        if let Some(city) = &self.city {
+            if provider.city.trim().to_lowercase() != city.trim().to_lowercase() {
+                return false;
+            }
+        }
+
+        if let Some(name) = &self.name {
+            let full_name = format!("{} {}", provider.first_name, provider.last_name);
+            if !full_name.to_lowercase().contains(&name.to_lowercase()) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+// One level of the sort order.
+#[derive(Clone, Copy)]
+pub struct SortKey<'a> {
+    pub ranker: &'a dyn Ranker,
+    pub order: Order,
+}
+
+impl<'a> SortKey<'a> {
+    // Sorts in the direction the ranker considers best first.
+    pub fn natural(ranker: &'a dyn Ranker) -> Self {
+        SortKey {
+            ranker,
+            order: ranker.order(),
+        }
+    }
+
+    // Parses a comma separated list like `profile:desc,distance`. Keys without a
+    // direction use the ranker's own, `order` overrides the direction of the first key.
+    pub fn parse(
+        spec: &str,
+        order: Option<&str>,
+        rankers: &'a Rankers,
+    ) -> Result<Vec<SortKey<'a>>, String> {
+        let mut keys = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|key| {
+                let (name, direction) = match key.split_once(':') {
+                    Some((name, direction)) => (name, Some(direction)),
+                    None => (key, None),
+                };
+
+                let ranker = rankers
+                    .get(name)
+                    .ok_or_else(|| format!("Unknown sort `{name}`."))?;
+
+                Ok(match direction {
+                    Some(direction) => SortKey {
+                        ranker,
+                        order: parse_order(direction)?,
+                    },
+                    None => SortKey::natural(ranker),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        if keys.is_empty() {
+            return Err("No sort key given.".to_string());
+        }
+
+        if let Some(order) = order {
+            keys[0].order = parse_order(order)?;
+        }
+
+        Ok(keys)
+    }
+}
+
+fn parse_order(order: &str) -> Result<Order, String> {
+    match order.trim().to_ascii_lowercase().as_str() {
+        "asc" | "ascending" => Ok(Order::Ascending),
+        "desc" | "descending" => Ok(Order::Descending),
+        _ => Err(format!("Unknown order `{order}`, use `asc` or `desc`.")),
+    }
+}
+
+// Compares the scores of two providers key by key.
+pub fn compare_scores(keys: &[SortKey], a: &[f64], b: &[f64]) -> Ordering {
+    keys.iter()
+        .zip(a.iter().zip(b))
+        .map(|(key, (a, b))| match key.order {
+            Order::Ascending => a.total_cmp(b),
+            Order::Descending => b.total_cmp(a),
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+// A provider in range along with its scores, one per sort key.
+pub struct Ranked {
+    pub id: u32,
+    pub name: String,
+    pub scores: Vec<f64>,
+}
+
+impl Ranked {
+    // The ranking score shown is the one of the first sort key.
+    pub fn view(self) -> ServiceProviderView {
+        ServiceProviderView {
+            id: self.id,
+            name: self.name,
+            ranking_score: self.scores.first().copied().unwrap_or_default(),
+        }
+    }
+}
+
+// Position after the last result of a page. Scores are kept as raw bits so the
+// comparison on the next request is exact.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Cursor {
+    // `search_key` of the search the cursor was issued for
+    #[serde(rename = "q")]
+    search: u64,
+    // Unix timestamp the scores were computed at
+    #[serde(rename = "t")]
+    at: i64,
+    #[serde(rename = "s")]
+    scores: Vec<u64>,
+    id: u32,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+    }
+
+    pub fn decode(token: &str) -> Result<Cursor, String> {
+        URL_SAFE_NO_PAD
+            .decode(token)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| "Invalid cursor.".to_string())
+    }
+
+    pub fn at(&self) -> i64 {
+        self.at
+    }
+}
+
+pub struct Page {
+    pub items: Vec<Ranked>,
+    pub next_cursor: Option<String>,
+}
+
+// Identifies a search by its sort order, postcode and filter, so a cursor is only
+// accepted for the search it came from.
+pub fn search_key(keys: &[SortKey], profile: &str, postcode: PostalCode, filter: &Filter) -> u64 {
+    let keys: Vec<String> = keys
+        .iter()
+        .map(|key| {
+            let order = match key.order {
+                Order::Ascending => "asc",
+                Order::Descending => "desc",
+            };
+            format!("{}:{order}", key.ranker.name())
+        })
+        .collect();
+
+    let mut hasher = DefaultHasher::new();
+    format!("{}@{profile}/{postcode}/{filter:?}", keys.join(",")).hash(&mut hasher);
+    hasher.finish()
+}
+
+// Cuts the page following `after` out of the sorted results. Starting from the last
+// position rather than an offset keeps pages from overlapping when scores change in between.
+pub fn paginate(
+    ranked: Vec<Ranked>,
+    keys: &[SortKey],
+    search: u64,
+    at: i64,
+    after: Option<&Cursor>,
+    limit: usize,
+) -> Result<Page, String> {
+    let start = match after {
+        Some(cursor) => {
+            if cursor.search != search || cursor.scores.len() != keys.len() {
+                return Err("The cursor belongs to a different search.".to_string());
+            }
+
+            let scores: Vec<f64> = cursor.scores.iter().map(|s| f64::from_bits(*s)).collect();
+            ranked.partition_point(|item| {
+                compare_scores(keys, &item.scores, &scores)
+                    .then(item.id.cmp(&cursor.id))
+                    .is_le()
+            })
+        }
+        None => 0,
+    };
+
+    let mut items: Vec<Ranked> = ranked.into_iter().skip(start).take(limit + 1).collect();
+    let has_more = items.len() > limit;
+    items.truncate(limit);
+
+    let next_cursor = match items.last() {
+        Some(last) if has_more => Some(
+            Cursor {
+                search,
+                at,
+                scores: last.scores.iter().map(|s| s.to_bits()).collect(),
+                id: last.id,
+            }
+            .encode(),
+        ),
+        _ => None,
+    };
+
+    Ok(Page { items, next_cursor })
+}
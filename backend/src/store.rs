@@ -0,0 +1,211 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::data::{Address, NewServiceProvider, PostalCode};
+use crate::geo::Geometry;
+use crate::validation::{validate_distance, validate_travel_time, Invalid};
+
+// A change made through the API on top of the base datasets. Fields are camelCase like
+// the request bodies the create and relocate entries are made of. Logs written before
+// had snake_case updates and numeric postcodes, which are still read.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Mutation {
+    #[serde(rename_all = "camelCase")]
+    Update {
+        id: u32,
+        #[serde(
+            alias = "max_driving_distance",
+            skip_serializing_if = "Option::is_none"
+        )]
+        max_driving_distance: Option<u64>,
+        // Minutes, 0 removes the travel time
+        #[serde(
+            default,
+            alias = "max_travel_time",
+            skip_serializing_if = "Option::is_none"
+        )]
+        max_travel_time: Option<u64>,
+        #[serde(
+            alias = "profile_picture_score",
+            skip_serializing_if = "Option::is_none"
+        )]
+        profile_picture_score: Option<f64>,
+        #[serde(
+            alias = "profile_description_score",
+            skip_serializing_if = "Option::is_none"
+        )]
+        profile_description_score: Option<f64>,
+    },
+    Create {
+        id: u32,
+        #[serde(flatten, deserialize_with = "numeric_postcode")]
+        provider: NewServiceProvider,
+    },
+    Relocate {
+        id: u32,
+        #[serde(flatten, deserialize_with = "numeric_postcode")]
+        address: Address,
+    },
+    #[serde(rename_all = "camelCase")]
+    Areas {
+        id: u32,
+        #[serde(alias = "service_area", skip_serializing_if = "Option::is_none")]
+        service_area: Option<Geometry>,
+        #[serde(
+            default,
+            alias = "excluded_areas",
+            skip_serializing_if = "Vec::is_empty"
+        )]
+        excluded_areas: Vec<Geometry>,
+    },
+    Delete {
+        id: u32,
+    },
+}
+
+// Reads a flattened address with its postcode written as a number.
+fn numeric_postcode<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let mut value = serde_json::Value::deserialize(deserializer)?;
+    if let Some(postcode) = value.get_mut("postcode") {
+        let number = postcode.as_u64().and_then(|n| u32::try_from(n).ok());
+        if let Some(code) = number.and_then(PostalCode::from_number) {
+            *postcode = code.to_string().into();
+        }
+    }
+
+    T::deserialize(value).map_err(D::Error::custom)
+}
+
+impl Mutation {
+    pub fn id(&self) -> u32 {
+        match *self {
+            Mutation::Update { id, .. }
+            | Mutation::Create { id, .. }
+            | Mutation::Relocate { id, .. }
+            | Mutation::Areas { id, .. }
+            | Mutation::Delete { id } => id,
+        }
+    }
+
+    // Checks the bounds on reach the API enforces, for logs written before they did.
+    pub fn validate(&self) -> Result<(), Invalid> {
+        let (distance, minutes) = match self {
+            Mutation::Update {
+                max_driving_distance,
+                max_travel_time,
+                ..
+            } => (*max_driving_distance, *max_travel_time),
+            Mutation::Create { provider, .. } => (
+                Some(provider.max_driving_distance),
+                provider.max_travel_time,
+            ),
+            _ => (None, None),
+        };
+
+        distance.map_or(Ok(()), validate_distance)?;
+        minutes.map_or(Ok(()), validate_travel_time)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    // Unix timestamp (seconds) of when the mutation was made
+    pub at: i64,
+    #[serde(flatten)]
+    pub mutation: Mutation,
+}
+
+// Persists mutations so they can be replayed on top of freshly loaded datasets.
+pub trait Store: Send + Sync {
+    fn append(&self, entry: &Entry) -> io::Result<()>;
+
+    fn replay(&self) -> io::Result<Vec<Entry>>;
+}
+
+// Keeps nothing, mutations only live as long as the process.
+pub struct MemoryStore;
+
+impl Store for MemoryStore {
+    fn append(&self, _entry: &Entry) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn replay(&self) -> io::Result<Vec<Entry>> {
+        Ok(vec![])
+    }
+}
+
+// Append-only write-ahead log with one JSON entry per line.
+pub struct WalStore {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl WalStore {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        // A crash mid-write can only tear the last entry, cut it off before appending again.
+        let contents = fs::read(path)?;
+        if contents.last().is_some_and(|b| *b != b'\n') {
+            let keep = contents
+                .iter()
+                .rposition(|b| *b == b'\n')
+                .map_or(0, |i| i + 1);
+            eprintln!("Dropping incomplete last entry of {}.", path.display());
+            file.set_len(keep as u64)?;
+        }
+
+        Ok(WalStore {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl Store for WalStore {
+    fn append(&self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+
+    fn replay(&self) -> io::Result<Vec<Entry>> {
+        // Hold the lock so no entry is appended halfway through reading.
+        let _file = self.file.lock().unwrap();
+        let lines: Vec<String> = BufReader::new(File::open(&self.path)?)
+            .lines()
+            .collect::<io::Result<_>>()?;
+
+        lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} line {}: {e}", self.path.display(), i + 1),
+                    )
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use crate::data::{QualityFactor, ServiceProvider};
+
+// Synthetic data for the benchmark and the index tests.
+
+// Deterministic xorshift generator, so runs are comparable.
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // Uniform in [low, high)
+    pub fn range(&mut self, low: f64, high: f64) -> f64 {
+        low + (self.next() >> 11) as f64 / (1u64 << 53) as f64 * (high - low)
+    }
+}
+
+// A random position within Germany in radians.
+pub fn position(rng: &mut Rng) -> (f64, f64) {
+    (
+        rng.range(5.9, 15.0).to_radians(),
+        rng.range(47.3, 55.0).to_radians(),
+    )
+}
+
+// Providers with ids from 1 to `count`, placed by `place` with their driving distance.
+pub fn providers(
+    rng: &mut Rng,
+    count: u32,
+    mut place: impl FnMut(&mut Rng) -> ((f64, f64), u64),
+) -> (HashMap<u32, ServiceProvider>, HashMap<u32, QualityFactor>) {
+    let mut providers = HashMap::new();
+    let mut quality = HashMap::new();
+
+    for id in 1..=count {
+        let ((lon, lat), max_driving_distance) = place(rng);
+        providers.insert(
+            id,
+            ServiceProvider {
+                id,
+                first_name: format!("First{id}"),
+                last_name: format!("Last{id}"),
+                city: String::new(),
+                street: String::new(),
+                house_number: String::new(),
+                lon,
+                lat,
+                max_driving_distance,
+                max_travel_time: None,
+                service_area: None,
+                excluded_areas: Vec::new(),
+            },
+        );
+        quality.insert(
+            id,
+            QualityFactor {
+                profile_id: id,
+                profile_picture_score: rng.range(0.0, 1.0),
+                profile_description_score: rng.range(0.0, 1.0),
+            },
+        );
+    }
+
+    (providers, quality)
+}
@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::Hash;
+use std::path::PathBuf;
+
+use crate::data::{Address, PostalCode, Postcode, QualityFactor, ServiceProvider};
+use crate::geo::Geometry;
+use crate::import::RecordError;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Missing {
+        dataset: &'static str,
+    },
+    Io {
+        dataset: &'static str,
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Record {
+        dataset: &'static str,
+        error: RecordError,
+    },
+    Invalid {
+        dataset: &'static str,
+        id: String,
+        reason: Invalid,
+    },
+}
+
+// Reasons a well-formed record is still rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Invalid {
+    LatitudeOutOfRange(f64),
+    LongitudeOutOfRange(f64),
+    DuplicateId,
+    MissingQualityFactor,
+    MissingPosition,
+    Area(String),
+    DistanceOutOfRange(u64),
+    TravelTimeOutOfRange(u64),
+    NotPositive(&'static str, f64),
+    NoGroups,
+    ExtensionOutOfRange(u64),
+}
+
+impl LoadError {
+    pub fn dataset(&self) -> &'static str {
+        match self {
+            LoadError::Missing { dataset }
+            | LoadError::Io { dataset, .. }
+            | LoadError::Record { dataset, .. }
+            | LoadError::Invalid { dataset, .. } => dataset,
+        }
+    }
+
+    // Short category used to group errors in the report.
+    fn reason(&self) -> String {
+        match self {
+            LoadError::Missing { .. } => "missing dataset".to_string(),
+            LoadError::Io { .. } => "unreadable file".to_string(),
+            LoadError::Record { error, .. } => match &error.field {
+                Some(field) => format!("invalid `{field}`"),
+                None => "malformed record".to_string(),
+            },
+            LoadError::Invalid { reason, .. } => match reason {
+                Invalid::LatitudeOutOfRange(_) => "latitude out of range".to_string(),
+                Invalid::LongitudeOutOfRange(_) => "longitude out of range".to_string(),
+                Invalid::DuplicateId => "duplicate id".to_string(),
+                Invalid::MissingQualityFactor => "no quality factor".to_string(),
+                Invalid::MissingPosition => "no coordinates".to_string(),
+                Invalid::Area(_) => "invalid area".to_string(),
+                Invalid::DistanceOutOfRange(_) => "driving distance out of range".to_string(),
+                Invalid::TravelTimeOutOfRange(_) => "travel time out of range".to_string(),
+                Invalid::NotPositive(name, _) => format!("non-positive `{name}`"),
+                Invalid::NoGroups => "no groups".to_string(),
+                Invalid::ExtensionOutOfRange(_) => "extension out of range".to_string(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Invalid::LatitudeOutOfRange(lat) => write!(f, "latitude {lat} is out of range"),
+            Invalid::LongitudeOutOfRange(lon) => write!(f, "longitude {lon} is out of range"),
+            Invalid::DuplicateId => write!(f, "id is used more than once"),
+            Invalid::MissingQualityFactor => write!(f, "no quality factor for this provider"),
+            Invalid::MissingPosition => write!(f, "coordinates are missing"),
+            Invalid::Area(reason) => write!(f, "invalid area, {reason}"),
+            Invalid::DistanceOutOfRange(distance) => write!(
+                f,
+                "driving distance {distance} is above {MAX_DRIVING_DISTANCE} metres"
+            ),
+            Invalid::TravelTimeOutOfRange(minutes) => write!(
+                f,
+                "travel time {minutes} is above {MAX_TRAVEL_TIME} minutes"
+            ),
+            Invalid::NotPositive(name, value) => {
+                write!(f, "`{name}` must be positive, got {value}")
+            }
+            Invalid::NoGroups => write!(f, "at least one group is needed"),
+            Invalid::ExtensionOutOfRange(extension) => write!(
+                f,
+                "extension {extension} is above {MAX_DRIVING_DISTANCE} metres"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Missing { dataset } => write!(f, "No {dataset} data file configured."),
+            LoadError::Io {
+                dataset,
+                path,
+                source,
+            } => write!(
+                f,
+                "Could not read {dataset} data from {}: {source}",
+                path.display()
+            ),
+            LoadError::Record { dataset, error } => {
+                write!(f, "Failed to parse {dataset} data: {error}")
+            }
+            LoadError::Invalid {
+                dataset,
+                id,
+                reason,
+            } => write!(f, "Invalid {dataset} {id}: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io { source, .. } => Some(source),
+            LoadError::Record { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// A dataset row that can be checked on its own once it has been parsed.
+pub trait Record {
+    const DATASET: &'static str;
+
+    type Id: Copy + Eq + Hash + fmt::Display;
+
+    fn id(&self) -> Self::Id;
+
+    fn validate(&self) -> Result<(), Invalid> {
+        Ok(())
+    }
+}
+
+// Metres, about the distance to the antipode. Anything further reaches the whole earth
+// and would only overflow the reach of the index.
+pub const MAX_DRIVING_DISTANCE: u64 = 20_000_000;
+
+pub fn validate_distance(distance: u64) -> Result<(), Invalid> {
+    match distance > MAX_DRIVING_DISTANCE {
+        true => Err(Invalid::DistanceOutOfRange(distance)),
+        false => Ok(()),
+    }
+}
+
+// Minutes, a day of driving. Isochrones are searched for on the road graph up to this,
+// so anything longer only makes the search cover the whole graph.
+pub const MAX_TRAVEL_TIME: u64 = 24 * 60;
+
+pub fn validate_travel_time(minutes: u64) -> Result<(), Invalid> {
+    match minutes > MAX_TRAVEL_TIME {
+        true => Err(Invalid::TravelTimeOutOfRange(minutes)),
+        false => Ok(()),
+    }
+}
+
+pub fn validate_position(lon: f64, lat: f64) -> Result<(), Invalid> {
+    let (lon, lat) = (lon.to_degrees(), lat.to_degrees());
+
+    if !(-90.0..=90.0).contains(&lat) {
+        Err(Invalid::LatitudeOutOfRange(lat))
+    } else if !(-180.0..=180.0).contains(&lon) {
+        Err(Invalid::LongitudeOutOfRange(lon))
+    } else {
+        Ok(())
+    }
+}
+
+// Service areas need at least one polygon, each with closed rings within valid
+// coordinates.
+pub fn validate_area(area: &Geometry) -> Result<(), Invalid> {
+    if let Geometry::MultiPoint { .. } = area {
+        return Err(Invalid::Area(
+            "only a Polygon or MultiPolygon outlines an area".to_string(),
+        ));
+    }
+
+    let polygons = area.polygons();
+    if polygons.is_empty() {
+        return Err(Invalid::Area("there is no polygon".to_string()));
+    }
+    if polygons.iter().any(Vec::is_empty) {
+        return Err(Invalid::Area(
+            "every polygon needs an outer ring".to_string(),
+        ));
+    }
+
+    for ring in polygons.iter().flatten() {
+        if ring.len() < 4 || ring.first() != ring.last() {
+            return Err(Invalid::Area(
+                "rings need at least four positions, the last repeating the first".to_string(),
+            ));
+        }
+
+        for [lon, lat] in ring {
+            validate_position(lon.to_radians(), lat.to_radians())?;
+        }
+    }
+
+    Ok(())
+}
+
+impl Address {
+    pub fn validate(&self) -> Result<(), Invalid> {
+        let (lon, lat) = self.position().ok_or(Invalid::MissingPosition)?;
+        validate_position(lon.to_radians(), lat.to_radians())
+    }
+}
+
+impl Record for Postcode {
+    const DATASET: &'static str = "postcode";
+
+    type Id = PostalCode;
+
+    fn id(&self) -> PostalCode {
+        self.postcode
+    }
+
+    fn validate(&self) -> Result<(), Invalid> {
+        validate_position(self.lon, self.lat)
+    }
+}
+
+impl Record for ServiceProvider {
+    const DATASET: &'static str = "service provider";
+
+    type Id = u32;
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn validate(&self) -> Result<(), Invalid> {
+        validate_position(self.lon, self.lat)?;
+        validate_distance(self.max_driving_distance)?;
+        if let Some(minutes) = self.max_travel_time {
+            if minutes == 0 {
+                return Err(Invalid::NotPositive("max_travel_time", 0.0));
+            }
+            validate_travel_time(minutes)?;
+        }
+        self.service_area
+            .iter()
+            .chain(&self.excluded_areas)
+            .try_for_each(validate_area)
+    }
+}
+
+impl Record for QualityFactor {
+    const DATASET: &'static str = "quality factor";
+
+    type Id = u32;
+
+    fn id(&self) -> u32 {
+        self.profile_id
+    }
+}
+
+// Collects the records dropped while loading. In strict mode the first problem aborts
+// the load instead.
+#[derive(Debug)]
+pub struct ValidationReport {
+    lenient: bool,
+    dropped: Vec<LoadError>,
+}
+
+impl ValidationReport {
+    pub fn new(lenient: bool) -> Self {
+        ValidationReport {
+            lenient,
+            dropped: Vec::new(),
+        }
+    }
+
+    // Records `error` and skips the record in lenient mode, otherwise hands it back.
+    pub fn reject(&mut self, error: LoadError) -> Result<(), LoadError> {
+        if self.lenient {
+            self.dropped.push(error);
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+
+    pub fn dropped(&self) -> &[LoadError] {
+        &self.dropped
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.dropped.is_empty() {
+            return write!(f, "Validation report: all records loaded.");
+        }
+
+        let mut counts: BTreeMap<(&str, String), usize> = BTreeMap::new();
+        for error in &self.dropped {
+            *counts.entry((error.dataset(), error.reason())).or_default() += 1;
+        }
+
+        write!(
+            f,
+            "Validation report: dropped {} records.",
+            self.dropped.len()
+        )?;
+        for ((dataset, reason), count) in counts {
+            write!(f, "\n  {dataset}: {count} x {reason}")?;
+        }
+
+        const SHOWN: usize = 20;
+        for error in self.dropped.iter().take(SHOWN) {
+            write!(f, "\n  - {error}")?;
+        }
+        if self.dropped.len() > SHOWN {
+            write!(f, "\n  ... and {} more", self.dropped.len() - SHOWN)?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 export interface ZipcodeSearchResultItem {
-  zipcode: number;
+  zipcode: string;
   place: string;
   latitude: number;
   longitude: number;
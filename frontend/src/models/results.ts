@@ -8,6 +8,12 @@ export interface ServiceProvider {
   lon: number;
   lat: number;
   max_driving_distance: number;
+  max_travel_time?: number;
+}
+
+export interface Suggestion extends ServiceProvider {
+  distance: number;
+  extra_km: number;
 }
 
 export function getNormalCoords(sp: ServiceProvider): [number, number] {